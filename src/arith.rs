@@ -0,0 +1,84 @@
+use std::cmp::Ordering;
+use crate::Value;
+
+// Number conversions and comparisons shared by the interpreter and the optimizer, so that
+// folding a constant expression never changes what it evaluates to
+
+pub fn is_truthy(v: &Value) -> bool {
+    !matches!(v, Value::Nil | Value::Boolean(false))
+}
+
+pub fn to_float(v: &Value) -> Option<f64> {
+    match v {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Number(n) => Some(n.0),
+        _ => None,
+    }
+}
+
+// Floats convert only if they have an exact integer representation
+pub fn to_integer(v: &Value) -> Option<i64> {
+    match v {
+        Value::Integer(i) => Some(*i),
+        Value::Number(n) if n.fract() == 0.0 && n.0 >= i64::MIN as f64 && n.0 < i64::MAX as f64 => Some(n.0 as i64),
+        _ => None,
+    }
+}
+
+// Logical shift, shifting by 64 or more bits clears every bit and negative amounts shift the other way
+pub fn shift_left(x: i64, n: i64) -> i64 {
+    if n <= -64 || n >= 64 {
+        0
+    } else if n >= 0 {
+        ((x as u64) << n) as i64
+    } else {
+        ((x as u64) >> -n) as i64
+    }
+}
+
+// Orders two numbers or two strings. None if they are neither, `Some(None)` if NaN leaves them unordered.
+// Integers and floats are compared by their exact values, not by converting the integer to a float
+pub fn compare_values(l: &Value, r: &Value) -> Option<Option<Ordering>> {
+    match (l, r) {
+        (Value::String(a), Value::String(b)) => Some(Some(a.as_bytes().cmp(b.as_bytes()))),
+        _ => compare_numbers(l, r),
+    }
+}
+
+pub fn compare_numbers(l: &Value, r: &Value) -> Option<Option<Ordering>> {
+    Some(match (l, r) {
+        (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+        (Value::Number(a), Value::Number(b)) => a.0.partial_cmp(&b.0),
+        (Value::Integer(i), Value::Number(f)) => compare_int_float(*i, f.0),
+        (Value::Number(f), Value::Integer(i)) => compare_int_float(*i, f.0).map(Ordering::reverse),
+        _ => return None,
+    })
+}
+
+fn compare_int_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        return None;
+    }
+    // 2^63, the first float above every i64
+    const LIMIT: f64 = 9223372036854775808.0;
+    if f >= LIMIT {
+        return Some(Ordering::Less);
+    } else if f < -LIMIT {
+        return Some(Ordering::Greater);
+    }
+    let floor = f.floor();
+    match i.cmp(&(floor as i64)) {
+        Ordering::Equal if f > floor => Some(Ordering::Less),
+        o => Some(o),
+    }
+}
+
+// Equality without metamethods
+pub fn raw_equals(l: &Value, r: &Value) -> bool {
+    match (l, r) {
+        (Value::Integer(_) | Value::Number(_), Value::Integer(_) | Value::Number(_)) => {
+            compare_numbers(l, r) == Some(Some(Ordering::Equal))
+        },
+        _ => l == r,
+    }
+}
@@ -0,0 +1,516 @@
+use crate::lexer::{Lexer, LexError};
+use crate::span::Span;
+use crate::stmt::{StmtKind, LocalAttrib};
+use crate::expr::{ExprKind, Field};
+use crate::{Expr, Stmt, Token, Value};
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum DumpFormat {
+    Sexpr,
+    Json,
+}
+
+impl DumpFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sexpr" => Some(DumpFormat::Sexpr),
+            "json" => Some(DumpFormat::Json),
+            _ => None
+        }
+    }
+}
+
+// Lists what `Lexer::tokenize` produces, one token per line: position, kind and source text
+pub fn dump_tokens<S: AsRef<[u8]> + ?Sized>(src: &S) -> Result<String, LexError> {
+    let bytes = src.as_ref();
+    let tokens = Lexer::new(src).tokenize()?;
+    let mut ret = String::new();
+    for t in tokens.iter() {
+        let position = format!("{}:{}", t.span.line, t.span.column);
+        let mut text = String::new();
+        for chunk in bytes[t.span.start..t.span.end].utf8_chunks() {
+            let valid = chunk.valid().replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r").replace('\t', "\\t");
+            text.push_str(&valid);
+            escape_invalid(chunk.invalid(), &mut text);
+        }
+        ret.push_str(&format!("{:<9} {:<19} {}\n", position, token_name(&t.token), text));
+    }
+    Ok(ret)
+}
+
+// Prints a syntax tree, usually the chunk returned by `Parser::chunk`. Node names are the names of the
+// `StmtKind` and `ExprKind` variants. Only the JSON output carries source positions. Strings that are not
+// valid UTF-8 keep their bytes: s-expressions escape the invalid ones as `\xNN`, JSON writes the whole
+// string as `{"bytes": [...]}`.
+pub fn dump_ast(stmt: &Stmt, format: DumpFormat) -> String {
+    let node = stmt_node(stmt);
+    let mut ret = String::new();
+    match format {
+        DumpFormat::Sexpr => write_sexpr(&node, 0, &mut ret),
+        DumpFormat::Json => write_json(&node, 0, &mut ret),
+    }
+    ret.push('\n');
+    ret
+}
+
+fn token_name(token: &Token) -> &'static str {
+    match token {
+        Token::Literal(Value::String(_)) => "String",
+        Token::Literal(Value::Integer(_)) => "Integer",
+        Token::Literal(Value::Number(_)) => "Number",
+        Token::Literal(Value::Boolean(_)) => "Boolean",
+        Token::Literal(Value::Nil) => "Nil",
+        Token::Literal(_) => "Literal",
+        Token::Identifier(_) => "Identifier",
+        Token::Assign => "Assign",
+        Token::Comma => "Comma",
+        Token::Plus => "Plus",
+        Token::Minus => "Minus",
+        Token::Star => "Star",
+        Token::ForwardSlash => "ForwardSlash",
+        Token::Equals => "Equals",
+        Token::NotEquals => "NotEquals",
+        Token::LessThan => "LessThan",
+        Token::GreaterThan => "GreaterThan",
+        Token::LessThanOrEqual => "LessThanOrEqual",
+        Token::GreaterThanOrEqual => "GreaterThanOrEqual",
+        Token::LeftParens => "LeftParens",
+        Token::RightParens => "RightParens",
+        Token::Semicolon => "Semicolon",
+        Token::Concatenation => "Concatenation",
+        Token::Do => "Do",
+        Token::End => "End",
+        Token::And => "And",
+        Token::Or => "Or",
+        Token::Not => "Not",
+        Token::Function => "Function",
+        Token::Return => "Return",
+        Token::Break => "Break",
+        Token::Goto => "Goto",
+        Token::Nil => "Nil",
+        Token::False => "False",
+        Token::True => "True",
+        Token::Local => "Local",
+        Token::While => "While",
+        Token::Repeat => "Repeat",
+        Token::Until => "Until",
+        Token::If => "If",
+        Token::Then => "Then",
+        Token::Else => "Else",
+        Token::Elseif => "Elseif",
+        Token::For => "For",
+        Token::In => "In",
+        Token::LeftCurlyBrace => "LeftCurlyBrace",
+        Token::RightCurlyBrace => "RightCurlyBrace",
+        Token::Period => "Period",
+        Token::LeftSquareBracket => "LeftSquareBracket",
+        Token::RightSquareBracket => "RightSquareBracket",
+        Token::Pound => "Pound",
+        Token::Varargs => "Varargs",
+        Token::Percent => "Percent",
+        Token::Caret => "Caret",
+        Token::DoubleForwardSlash => "DoubleForwardSlash",
+        Token::Ampersand => "Ampersand",
+        Token::Pipe => "Pipe",
+        Token::Tilde => "Tilde",
+        Token::ShiftLeft => "ShiftLeft",
+        Token::ShiftRight => "ShiftRight",
+        Token::Colon => "Colon",
+        Token::DoubleColon => "DoubleColon",
+    }
+}
+
+// Format independent form of the tree, both outputs are rendered from it
+enum Node {
+    Tree(&'static str, Span, Vec<(&'static str, Node)>),
+    List(Vec<Node>),
+    // Contents of a Lua string, quoted in both outputs
+    Str(Vec<u8>),
+    // Names and operators, bare in s-expressions
+    Name(String),
+    Number(String),
+    Bool(bool),
+    Nil,
+}
+
+fn stmts_node(stmts: &[Stmt]) -> Node {
+    Node::List(stmts.iter().map(stmt_node).collect())
+}
+
+fn stmt_node(stmt: &Stmt) -> Node {
+    let (kind, fields) = match &stmt.kind {
+        StmtKind::Break => ("Break", vec![]),
+        StmtKind::Goto(label) => ("Goto", vec![("label", Node::Name(label.clone()))]),
+        StmtKind::Label(name) => ("Label", vec![("name", Node::Name(name.clone()))]),
+        StmtKind::Empty => ("Empty", vec![]),
+        StmtKind::ExprStmt(expr) => ("ExprStmt", vec![("expr", expr_node(expr))]),
+        StmtKind::Assignment(targets, values) => ("Assignment", vec![("targets", expr_node(targets)), ("values", expr_node(values))]),
+        StmtKind::LocalAssignment(names, values, attribs) => {
+            let attribs = attribs.iter().map(|a| match a {
+                Some(LocalAttrib::Const) => Node::Name("const".into()),
+                Some(LocalAttrib::Close) => Node::Name("close".into()),
+                None => Node::Nil,
+            }).collect();
+            ("LocalAssignment", vec![("names", expr_node(names)), ("values", expr_node(values)), ("attribs", Node::List(attribs))])
+        },
+        StmtKind::LocalFunction(name, function) => {
+            let name = if let ExprKind::Var(name, _) = &name.kind { Node::Name(name.clone()) } else { expr_node(name) };
+            ("LocalFunction", vec![("name", name), ("function", expr_node(function))])
+        },
+        StmtKind::Block(stmts) => ("Block", vec![("body", stmts_node(stmts))]),
+        StmtKind::DoBlock(stmts) => ("DoBlock", vec![("body", stmts_node(stmts))]),
+        StmtKind::Chunk(stmts) => ("Chunk", vec![("body", stmts_node(stmts))]),
+        StmtKind::IfStmt(cond, body, else_body) => {
+            ("IfStmt", vec![("condition", expr_node(cond)), ("body", stmt_node(body)), ("else", stmt_node(else_body))])
+        },
+        StmtKind::WhileLoop(cond, body) => ("WhileLoop", vec![("condition", expr_node(cond)), ("body", stmt_node(body))]),
+        StmtKind::RepeatUntilLoop(body, cond) => ("RepeatUntilLoop", vec![("body", stmt_node(body)), ("condition", expr_node(cond))]),
+        StmtKind::NumericForLoop(var, start, limit, step, body) => ("NumericForLoop", vec![
+            ("var", expr_node(var)),
+            ("start", expr_node(start)),
+            ("limit", expr_node(limit)),
+            ("step", expr_node(step)),
+            ("body", stmts_node(body)),
+        ]),
+        StmtKind::GenericForLoop(names, exprs, body) => {
+            ("GenericForLoop", vec![("names", expr_node(names)), ("exprs", expr_node(exprs)), ("body", stmts_node(body))])
+        },
+        StmtKind::Return(values) => ("Return", vec![("values", expr_node(values))]),
+    };
+    Node::Tree(kind, stmt.span, fields)
+}
+
+fn exprs_node(exprs: &[Expr]) -> Node {
+    Node::List(exprs.iter().map(expr_node).collect())
+}
+
+fn expr_node(expr: &Expr) -> Node {
+    let (kind, fields) = match &expr.kind {
+        ExprKind::Binary(left, op, right) => {
+            ("Binary", vec![("left", expr_node(left)), ("operator", Node::Name(op.to_string())), ("right", expr_node(right))])
+        },
+        ExprKind::Unary(operand, op) => ("Unary", vec![("operator", Node::Name(op.to_string())), ("operand", expr_node(operand))]),
+        ExprKind::Literal(Value::FunctionDef(function)) => {
+            let name = function.get_name().map_or(Node::Nil, Node::Name);
+            ("Function", vec![("name", name), ("params", exprs_node(function.get_params())), ("body", stmt_node(function.get_body()))])
+        },
+        ExprKind::Literal(value) => ("Literal", vec![("value", value_node(value))]),
+        ExprKind::Grouping(inner) => ("Grouping", vec![("expr", expr_node(inner))]),
+        ExprKind::Var(name, _) => ("Var", vec![("name", Node::Name(name.clone()))]),
+        ExprKind::Exprlist(exprs) => ("Exprlist", vec![("exprs", exprs_node(exprs))]),
+        ExprKind::FunctionCall(callee, args) => ("FunctionCall", vec![("callee", expr_node(callee)), ("args", exprs_node(args))]),
+        ExprKind::MethodCall(receiver, name, args) => {
+            ("MethodCall", vec![("receiver", expr_node(receiver)), ("method", Node::Name(name.clone())), ("args", exprs_node(args))])
+        },
+        ExprKind::Accessor(table, key) => ("Accessor", vec![("table", expr_node(table)), ("key", expr_node(key))]),
+        ExprKind::FieldList(fields) => {
+            let fields = fields.iter().map(|field| match field {
+                Field::Positional(value) => Node::Tree("Positional", value.span, vec![("value", expr_node(value))]),
+                Field::Keyed(key, value) => {
+                    Node::Tree("Keyed", key.span.to(value.span), vec![("key", expr_node(key)), ("value", expr_node(value))])
+                },
+            }).collect();
+            ("FieldList", vec![("fields", Node::List(fields))])
+        },
+        ExprKind::Varargs => ("Varargs", vec![]),
+    };
+    Node::Tree(kind, expr.span, fields)
+}
+
+fn value_node(value: &Value) -> Node {
+    match value {
+        Value::Nil => Node::Nil,
+        Value::Boolean(b) => Node::Bool(*b),
+        Value::Integer(_) | Value::Number(_) => Node::Number(value.to_string()),
+        Value::String(s) => Node::Str(s.as_bytes().to_vec()),
+        // Only the runtime creates other values
+        _ => Node::Name("<runtime value>".into()),
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut ret = String::from("\"");
+    escape_str(s, &mut ret);
+    ret.push('"');
+    ret
+}
+
+fn quote_bytes(bytes: &[u8]) -> String {
+    let mut ret = String::from("\"");
+    for chunk in bytes.utf8_chunks() {
+        escape_str(chunk.valid(), &mut ret);
+        escape_invalid(chunk.invalid(), &mut ret);
+    }
+    ret.push('"');
+    ret
+}
+
+fn escape_str(s: &str, ret: &mut String) {
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+}
+
+fn escape_invalid(bytes: &[u8], ret: &mut String) {
+    for b in bytes {
+        ret.push_str(&format!("\\x{b:02x}"));
+    }
+}
+
+const SEXPR_WIDTH: usize = 80;
+
+// Nodes that fit on the rest of the line are printed flat, others get one child per line
+fn write_sexpr(node: &Node, indent: usize, out: &mut String) {
+    let flat = sexpr_flat(node);
+    if indent + flat.len() <= SEXPR_WIDTH {
+        out.push_str(&flat);
+        return;
+    }
+    let (open, close, children): (String, &str, Vec<&Node>) = match node {
+        Node::Tree(kind, _, fields) => (format!("({kind}"), ")", fields.iter().map(|(_, n)| n).collect()),
+        Node::List(nodes) => ("[".into(), "]", nodes.iter().collect()),
+        _ => {
+            out.push_str(&flat);
+            return;
+        }
+    };
+    out.push_str(&open);
+    // A list's elements line up with its first one, which stays next to the bracket
+    let is_list = matches!(node, Node::List(_));
+    let child_indent = if is_list { indent + 1 } else { indent + 2 };
+    for (i, child) in children.iter().enumerate() {
+        if i > 0 || !is_list {
+            out.push('\n');
+            out.push_str(&" ".repeat(child_indent));
+        }
+        write_sexpr(child, child_indent, out);
+    }
+    out.push_str(close);
+}
+
+fn sexpr_flat(node: &Node) -> String {
+    match node {
+        Node::Tree(kind, _, fields) => {
+            let mut ret = format!("({kind}");
+            for (_, child) in fields.iter() {
+                ret.push(' ');
+                ret.push_str(&sexpr_flat(child));
+            }
+            ret.push(')');
+            ret
+        },
+        Node::List(nodes) => format!("[{}]", nodes.iter().map(sexpr_flat).collect::<Vec<_>>().join(" ")),
+        Node::Str(s) => quote_bytes(s),
+        Node::Name(s) | Node::Number(s) => s.clone(),
+        Node::Bool(b) => b.to_string(),
+        Node::Nil => "nil".into(),
+    }
+}
+
+fn write_json(node: &Node, indent: usize, out: &mut String) {
+    let pad = " ".repeat(indent + 2);
+    match node {
+        Node::Tree(kind, span, fields) => {
+            out.push_str("{\n");
+            out.push_str(&format!("{pad}\"kind\": {},\n", quote(kind)));
+            out.push_str(&format!("{pad}\"span\": {{\"line\": {}, \"column\": {}, \"end_line\": {}, \"end_column\": {}}}",
+                span.line, span.column, span.end_line, span.end_column));
+            for (name, child) in fields.iter() {
+                out.push_str(&format!(",\n{pad}{}: ", quote(name)));
+                write_json(child, indent + 2, out);
+            }
+            out.push('\n');
+            out.push_str(&" ".repeat(indent));
+            out.push('}');
+        },
+        Node::List(nodes) if nodes.is_empty() => out.push_str("[]"),
+        Node::List(nodes) => {
+            out.push('[');
+            for (i, child) in nodes.iter().enumerate() {
+                out.push_str(if i == 0 { "\n" } else { ",\n" });
+                out.push_str(&pad);
+                write_json(child, indent + 2, out);
+            }
+            out.push('\n');
+            out.push_str(&" ".repeat(indent));
+            out.push(']');
+        },
+        Node::Str(s) => match std::str::from_utf8(s) {
+            Ok(s) => out.push_str(&quote(s)),
+            Err(_) => {
+                let bytes = s.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(", ");
+                out.push_str(&format!("{{\"bytes\": [{bytes}]}}"));
+            },
+        },
+        Node::Name(s) => out.push_str(&quote(s)),
+        // JSON has no infinities, those are written as strings
+        Node::Number(n) if n.parse::<f64>().is_ok_and(|f| f.is_finite()) => out.push_str(n),
+        Node::Number(n) => out.push_str(&quote(n)),
+        Node::Bool(b) => out.push_str(&b.to_string()),
+        Node::Nil => out.push_str("null"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn parse(src: &[u8]) -> Stmt {
+        let tokens = Lexer::new(src).tokenize().expect("source should lex");
+        Parser::new(tokens).chunk().ok().expect("source should parse")
+    }
+
+    #[test]
+    fn tokens() {
+        let src = "local s = \"a\\tb\" -- c\nx = s .. 1.5\n";
+        assert_eq!(dump_tokens(src).expect("source should lex"), r#"1:1       Local               local
+1:7       Identifier          s
+1:9       Assign              =
+1:11      String              "a\\tb"
+2:1       Identifier          x
+2:3       Assign              =
+2:5       Identifier          s
+2:7       Concatenation       ..
+2:10      Number              1.5
+"#);
+    }
+
+    #[test]
+    fn tokens_keep_bytes() {
+        assert_eq!(dump_tokens(b"x = '\xe9'\n").expect("source should lex"), r#"1:1       Identifier          x
+1:3       Assign              =
+1:5       String              '\xe9'
+"#);
+    }
+
+    #[test]
+    fn sexpr() {
+        let chunk = parse(b"local t <const> = {1, k = \"v\", [2] = ...}\nreturn t.k\n");
+        assert_eq!(dump_ast(&chunk, DumpFormat::Sexpr), r#"(Chunk
+  [(LocalAssignment
+     (Exprlist [(Var t)])
+     (Exprlist
+       [(FieldList
+          [(Positional (Literal 1))
+           (Keyed (Literal "k") (Literal "v"))
+           (Keyed (Literal 2) (Varargs))])])
+     [const])
+   (Return (Exprlist [(Accessor (Var t) (Literal "k"))]))])
+"#);
+    }
+
+    #[test]
+    fn sexpr_escapes_strings() {
+        let chunk = parse(b"x = \"q\\\"b\\\\n\\n\\1\", '\xff'\n");
+        assert_eq!(dump_ast(&chunk, DumpFormat::Sexpr), r#"(Chunk
+  [(Assignment
+     (Exprlist [(Var x)])
+     (Exprlist [(Literal "q\"b\\n\n\u0001") (Literal "\xff")]))])
+"#);
+    }
+
+    #[test]
+    fn json() {
+        let chunk = parse(b"f(nil, true, -1.5)\n");
+        assert_eq!(dump_ast(&chunk, DumpFormat::Json), r#"{
+  "kind": "Chunk",
+  "span": {"line": 1, "column": 1, "end_line": 1, "end_column": 19},
+  "body": [
+    {
+      "kind": "ExprStmt",
+      "span": {"line": 1, "column": 1, "end_line": 1, "end_column": 19},
+      "expr": {
+        "kind": "Exprlist",
+        "span": {"line": 1, "column": 1, "end_line": 1, "end_column": 19},
+        "exprs": [
+          {
+            "kind": "FunctionCall",
+            "span": {"line": 1, "column": 1, "end_line": 1, "end_column": 19},
+            "callee": {
+              "kind": "Var",
+              "span": {"line": 1, "column": 1, "end_line": 1, "end_column": 2},
+              "name": "f"
+            },
+            "args": [
+              {
+                "kind": "Literal",
+                "span": {"line": 1, "column": 3, "end_line": 1, "end_column": 6},
+                "value": null
+              },
+              {
+                "kind": "Literal",
+                "span": {"line": 1, "column": 8, "end_line": 1, "end_column": 12},
+                "value": true
+              },
+              {
+                "kind": "Unary",
+                "span": {"line": 1, "column": 14, "end_line": 1, "end_column": 18},
+                "operator": "-",
+                "operand": {
+                  "kind": "Literal",
+                  "span": {"line": 1, "column": 15, "end_line": 1, "end_column": 18},
+                  "value": 1.5
+                }
+              }
+            ]
+          }
+        ]
+      }
+    }
+  ]
+}
+"#);
+    }
+
+    #[test]
+    fn json_escapes_strings() {
+        let chunk = parse(b"x = \"q\\\"b\\\\\\n\\1\", 'caf\xe9'\n");
+        assert_eq!(dump_ast(&chunk, DumpFormat::Json), r#"{
+  "kind": "Chunk",
+  "span": {"line": 1, "column": 1, "end_line": 1, "end_column": 25},
+  "body": [
+    {
+      "kind": "Assignment",
+      "span": {"line": 1, "column": 1, "end_line": 1, "end_column": 25},
+      "targets": {
+        "kind": "Exprlist",
+        "span": {"line": 1, "column": 1, "end_line": 1, "end_column": 4},
+        "exprs": [
+          {
+            "kind": "Var",
+            "span": {"line": 1, "column": 1, "end_line": 1, "end_column": 2},
+            "name": "x"
+          }
+        ]
+      },
+      "values": {
+        "kind": "Exprlist",
+        "span": {"line": 1, "column": 5, "end_line": 1, "end_column": 25},
+        "exprs": [
+          {
+            "kind": "Literal",
+            "span": {"line": 1, "column": 5, "end_line": 1, "end_column": 17},
+            "value": "q\"b\\\n\u0001"
+          },
+          {
+            "kind": "Literal",
+            "span": {"line": 1, "column": 19, "end_line": 1, "end_column": 25},
+            "value": {"bytes": [99, 97, 102, 233]}
+          }
+        ]
+      }
+    }
+  ]
+}
+"#);
+    }
+}
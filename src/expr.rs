@@ -1,14 +1,43 @@
-use crate::{Token, Value};
+use crate::{Token, Value, span::Span, resolver::VarKind};
 #[derive(Clone, PartialEq)]
-pub enum Expr {
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Clone, PartialEq)]
+pub enum ExprKind {
     Binary(Box<Expr>, Token, Box<Expr>),
     Unary(Box<Expr>, Token),
     Literal(Value),
     Grouping(Box<Expr>),
-    Var(String),
+    Var(String, VarKind /* where the resolver placed it, `Global` until it runs */),
     Exprlist(Vec<Expr>),
     FunctionCall(Box<Expr>, Vec<Expr>),
+    MethodCall(Box<Expr> /* Receiver, passed as self */, String, Vec<Expr>),
     Accessor(Box<Expr> /* Table being accessed */, Box<Expr> /* Expr that is accessing */),
-    FieldList(Vec<(Box<Expr>, Box<Expr>)>),
+    FieldList(Vec<Field>),
     Varargs,
 }
+
+// One entry of a table constructor
+#[derive(Clone, PartialEq)]
+pub enum Field {
+    // `value`, stored at the next integer index
+    Positional(Box<Expr>),
+    // `name = value` and `[key] = value`
+    Keyed(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+// Expressions created by the runtime have no place in the source
+impl From<ExprKind> for Expr {
+    fn from(kind: ExprKind) -> Self {
+        Self { kind, span: Span::default() }
+    }
+}
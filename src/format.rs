@@ -0,0 +1,472 @@
+use crate::lexer::{Lexer, LexError};
+use crate::parser::{Parser, ParseError};
+use crate::tokens::{Token, Trivia, TriviaKind, LosslessTokens};
+use crate::values::Value;
+use std::fmt::Display;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum QuoteStyle {
+    Double,
+    Single,
+    Preserve,
+}
+
+impl QuoteStyle {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "double" => Some(QuoteStyle::Double),
+            "single" => Some(QuoteStyle::Single),
+            "preserve" => Some(QuoteStyle::Preserve),
+            _ => None
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct FormatOptions {
+    pub indent_width: usize,
+    pub quote_style: QuoteStyle,
+    pub line_width: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self { indent_width: 4, quote_style: QuoteStyle::Double, line_width: 100 }
+    }
+}
+
+#[derive(Clone)]
+pub enum FormatError {
+    Lex(LexError),
+    Parse(Vec<ParseError>),
+    // The formatted source does not lex to the same tokens and comments as the input. Never expected, but nothing
+    // is written then.
+    Unstable,
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormatError::Lex(err) => write!(f, "{err}"),
+            FormatError::Parse(errors) => {
+                let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", messages.join("\n"))
+            },
+            FormatError::Unstable => write!(f, "formatting would change the meaning of the source"),
+        }
+    }
+}
+
+// Reformats Lua source. Line breaks chosen by the author are kept (runs of blank lines collapse into one),
+// while indentation, spacing between tokens and string quotes are normalised and lines longer than
+// `line_width` are broken up at their bracketed lists. Comments are kept where they are.
+// Only source that parses is formatted. Works on bytes, so comments and strings that are not valid UTF-8
+// come out unchanged.
+pub fn format<S: AsRef<[u8]> + ?Sized>(src: &S, options: &FormatOptions) -> Result<Vec<u8>, FormatError> {
+    let tokens = Lexer::new(src).tokenize().map_err(FormatError::Lex)?;
+    let (_, errors) = Parser::new(tokens).chunk_with_errors();
+    if !errors.is_empty() {
+        return Err(FormatError::Parse(errors));
+    }
+
+    let lossless = Lexer::tokenize_lossless(src).map_err(FormatError::Lex)?;
+    let mut formatter = Formatter::new(options);
+    for t in lossless.tokens.iter() {
+        for trivia in t.leading_trivia.iter() {
+            formatter.trivia(trivia);
+        }
+        formatter.token(&t.token.token, &t.text);
+    }
+    for trivia in lossless.trailing_trivia.iter() {
+        formatter.trivia(trivia);
+    }
+    let ret = formatter.finish();
+
+    let formatted = Lexer::tokenize_lossless(&ret).map_err(|_| FormatError::Unstable)?;
+    let same_tokens = formatted.tokens.len() == lossless.tokens.len()
+        && formatted.tokens.iter().zip(lossless.tokens.iter()).all(|(a, b)| a.token.token == b.token.token);
+    if !same_tokens || comments(&formatted) != comments(&lossless) {
+        return Err(FormatError::Unstable);
+    }
+    Ok(ret)
+}
+
+// The comments of a source in order, without the trailing whitespace the formatter drops
+fn comments(tokens: &LosslessTokens) -> Vec<&[u8]> {
+    let trivia = tokens.tokens.iter().flat_map(|t| t.leading_trivia.iter()).chain(tokens.trailing_trivia.iter());
+    trivia.filter_map(|t| match t.kind {
+        TriviaKind::LineComment | TriviaKind::Shebang => Some(t.text.trim_ascii_end()),
+        TriviaKind::BlockComment => Some(t.text.as_slice()),
+        TriviaKind::Whitespace | TriviaKind::Newline => None,
+    }).collect()
+}
+
+// A token or comment placed on an output line. Comments have no token.
+#[derive(Clone)]
+struct Item {
+    text: Vec<u8>,
+    token: Option<Token>,
+    space_before: bool,
+}
+
+#[derive(Clone)]
+struct Line {
+    indent: usize,
+    items: Vec<Item>,
+}
+
+// A block or bracket that is still open, with the indentation of the line that opened it
+struct Open {
+    token: Token,
+    indent: usize,
+}
+
+struct Formatter<'a> {
+    options: &'a FormatOptions,
+    // None stands for a blank line
+    lines: Vec<Option<Line>>,
+    current: Option<Line>,
+    open: Vec<Open>,
+    prev: Option<Token>,
+    prev_unary: bool,
+    // The current line holds nothing but closing tokens so far
+    leading_closers: bool,
+    // The last line ended in the middle of an expression or list
+    continuation: bool,
+    blank: bool,
+    // Between `local` and `=`, where `<` and `>` delimit attributes
+    in_local_names: bool,
+    // Between the two `::` of a label
+    in_label: bool,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(options: &'a FormatOptions) -> Self {
+        Self {
+            options,
+            lines: vec![],
+            current: None,
+            open: vec![],
+            prev: None,
+            prev_unary: false,
+            leading_closers: false,
+            continuation: false,
+            blank: false,
+            in_local_names: false,
+            in_label: false,
+        }
+    }
+
+    fn trivia(&mut self, trivia: &Trivia) {
+        match trivia.kind {
+            TriviaKind::Newline => {
+                if self.current.is_some() {
+                    self.flush();
+                } else if !self.lines.is_empty() {
+                    self.blank = true;
+                }
+            },
+            TriviaKind::Whitespace => {},
+            TriviaKind::LineComment | TriviaKind::Shebang => self.comment(trivia.text.trim_ascii_end()),
+            TriviaKind::BlockComment => self.comment(&trivia.text),
+        }
+    }
+
+    fn comment(&mut self, text: &[u8]) {
+        if self.current.is_none() {
+            self.start_line(self.body_indent());
+        }
+        if let Some(line) = self.current.as_mut() {
+            let space_before = !line.items.is_empty();
+            line.items.push(Item { text: text.to_vec(), token: None, space_before });
+        }
+    }
+
+    fn token(&mut self, token: &Token, text: &[u8]) {
+        let closes = matches!(token, Token::End | Token::Until | Token::Else | Token::Elseif
+            | Token::RightParens | Token::RightSquareBracket | Token::RightCurlyBrace);
+        if self.current.is_none() {
+            let continuation = if self.continuation && !closes { 1 } else { 0 };
+            self.start_line(self.body_indent() + continuation);
+        }
+        let unary = matches!(token, Token::Minus | Token::Pound | Token::Tilde | Token::Not)
+            && !self.prev.as_ref().is_some_and(ends_value);
+        let text = match token {
+            Token::Literal(Value::String(_)) => requote(text, self.options.quote_style),
+            _ => text.to_vec(),
+        };
+        let space_before = match (self.current.as_ref().and_then(|l| l.items.last()), &self.prev) {
+            (Some(Item { token: None, .. }), _) => true,
+            (None, _) | (_, None) => false,
+            (Some(_), Some(prev)) => self.space_between(prev, token, &text),
+        };
+
+        if closes {
+            if let Some(open) = self.open.pop() {
+                if self.leading_closers {
+                    if let Some(line) = self.current.as_mut() {
+                        line.indent = open.indent;
+                    }
+                }
+            }
+        } else {
+            self.leading_closers = false;
+        }
+        let indent = self.current.as_ref().map_or(0, |l| l.indent);
+        if let Some(line) = self.current.as_mut() {
+            line.items.push(Item { text, token: Some(token.clone()), space_before });
+        }
+        if matches!(token, Token::Function | Token::Do | Token::Then | Token::Repeat | Token::Else
+            | Token::LeftParens | Token::LeftSquareBracket | Token::LeftCurlyBrace) {
+            self.open.push(Open { token: token.clone(), indent });
+        }
+
+        match token {
+            Token::Local => self.in_local_names = true,
+            Token::Identifier(_) | Token::Comma | Token::LessThan | Token::GreaterThan => {},
+            _ => self.in_local_names = false,
+        }
+        if *token == Token::DoubleColon {
+            self.in_label = !self.in_label;
+        }
+        self.prev = Some(token.clone());
+        self.prev_unary = unary;
+    }
+
+    fn space_between(&self, prev: &Token, next: &Token, next_text: &[u8]) -> bool {
+        match (prev, next) {
+            (Token::LeftCurlyBrace, Token::RightCurlyBrace) => false,
+            (Token::LeftCurlyBrace, _) | (_, Token::RightCurlyBrace) => true,
+            // `[[` would start a long string
+            (Token::LeftSquareBracket, Token::Literal(Value::String(_))) if next_text.starts_with(b"[") => true,
+            (Token::LeftParens | Token::LeftSquareBracket | Token::Period | Token::Colon, _) => false,
+            (_, Token::RightParens | Token::RightSquareBracket | Token::Comma | Token::Semicolon | Token::Period | Token::Colon) => false,
+            // `--` would start a comment
+            (Token::Minus, Token::Minus) => true,
+            (Token::Minus | Token::Pound | Token::Tilde, _) if self.prev_unary => false,
+            (Token::DoubleColon, _) | (_, Token::DoubleColon) if self.in_label => false,
+            (Token::LessThan, _) | (_, Token::GreaterThan) if self.in_local_names => false,
+            (_, Token::LeftParens) => !matches!(prev, Token::Function) && !ends_callee(prev),
+            (_, Token::LeftSquareBracket) => !ends_callee(prev),
+            _ => true,
+        }
+    }
+
+    fn body_indent(&self) -> usize {
+        self.open.last().map_or(0, |o| o.indent + 1)
+    }
+
+    fn start_line(&mut self, indent: usize) {
+        if self.blank && !self.lines.is_empty() {
+            self.lines.push(None);
+        }
+        self.blank = false;
+        self.current = Some(Line { indent, items: vec![] });
+        self.leading_closers = true;
+    }
+
+    fn flush(&mut self) {
+        let Some(line) = self.current.take() else {
+            return;
+        };
+        // Lines holding only comments leave the continuation state alone
+        if line.items.iter().any(|i| i.token.is_some()) {
+            let in_brackets = matches!(self.open.last(), Some(Open { token: Token::LeftParens | Token::LeftSquareBracket | Token::LeftCurlyBrace, .. }));
+            let attrib_end = self.in_local_names && self.prev == Some(Token::GreaterThan);
+            self.continuation = !in_brackets && !attrib_end && self.prev.as_ref().is_some_and(continues_expression);
+        }
+        self.lines.push(Some(line));
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.flush();
+        let mut ret = vec![];
+        for line in self.lines.iter() {
+            match line {
+                Some(line) => self.render(line, &mut ret),
+                None => ret.push(b'\n'),
+            }
+        }
+        ret
+    }
+
+    fn render(&self, line: &Line, out: &mut Vec<u8>) {
+        let mut text = b" ".repeat(line.indent * self.options.indent_width);
+        for item in line.items.iter() {
+            if item.space_before {
+                text.push(b' ');
+            }
+            text.extend_from_slice(&item.text);
+        }
+        // Width in characters, UTF-8 continuation bytes do not count
+        let width = text.split(|b| *b == b'\n').next().map_or(0, |l| l.iter().filter(|b| **b & 0xc0 != 0x80).count());
+        if width > self.options.line_width {
+            if let Some(lines) = split_line(line) {
+                for l in lines.iter() {
+                    self.render(l, out);
+                }
+                return;
+            }
+        }
+        out.extend_from_slice(&text);
+        out.push(b'\n');
+    }
+}
+
+// Tokens after which a `-`, `~` or `#` is a binary operator
+fn ends_value(token: &Token) -> bool {
+    matches!(token, Token::Identifier(_) | Token::Literal(_) | Token::Varargs | Token::End
+        | Token::RightParens | Token::RightSquareBracket | Token::RightCurlyBrace)
+}
+
+// Tokens that can be directly followed by call arguments or an index
+fn ends_callee(token: &Token) -> bool {
+    matches!(token, Token::Identifier(_) | Token::Literal(Value::String(_))
+        | Token::RightParens | Token::RightSquareBracket | Token::RightCurlyBrace)
+}
+
+// Tokens that cannot end a statement, the next line continues what they started
+fn continues_expression(token: &Token) -> bool {
+    matches!(token, Token::Plus | Token::Minus | Token::Star | Token::ForwardSlash | Token::DoubleForwardSlash
+        | Token::Percent | Token::Caret | Token::Concatenation | Token::Equals | Token::NotEquals
+        | Token::LessThan | Token::GreaterThan | Token::LessThanOrEqual | Token::GreaterThanOrEqual
+        | Token::And | Token::Or | Token::Not | Token::Ampersand | Token::Pipe | Token::Tilde
+        | Token::ShiftLeft | Token::ShiftRight | Token::Assign | Token::Comma)
+}
+
+// Breaks a line at its first bracketed list that opens and closes on it, preferring one that holds several
+// elements. The elements go on their own lines, one level deeper.
+fn split_line(line: &Line) -> Option<Vec<Line>> {
+    let is_bracket = |item: &Item| matches!(item.token, Some(Token::LeftParens | Token::LeftCurlyBrace));
+    let mut stack: Vec<(usize, bool)> = vec![];
+    let mut first = None;
+    let mut chosen = None;
+    for (i, item) in line.items.iter().enumerate() {
+        match &item.token {
+            Some(Token::LeftParens | Token::LeftCurlyBrace | Token::LeftSquareBracket) => stack.push((i, false)),
+            Some(Token::Comma | Token::Semicolon) => {
+                if let Some(top) = stack.last_mut() {
+                    top.1 = true;
+                }
+            },
+            Some(Token::RightParens | Token::RightCurlyBrace | Token::RightSquareBracket) => {
+                if let Some((open, has_list)) = stack.pop() {
+                    if stack.is_empty() && i > open + 1 && is_bracket(&line.items[open]) {
+                        if has_list {
+                            chosen = Some((open, i));
+                            break;
+                        }
+                        first.get_or_insert((open, i));
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+    let (open, close) = chosen.or(first)?;
+
+    let start_line = |indent: usize, items: &[Item]| {
+        let mut items = items.to_vec();
+        if let Some(first) = items.first_mut() {
+            first.space_before = false;
+        }
+        Line { indent, items }
+    };
+    let mut lines = vec![start_line(line.indent, &line.items[..=open])];
+    let mut element_start = open + 1;
+    let mut depth = 0;
+    for i in open + 1..close {
+        match line.items[i].token {
+            Some(Token::LeftParens | Token::LeftCurlyBrace | Token::LeftSquareBracket) => depth += 1,
+            Some(Token::RightParens | Token::RightCurlyBrace | Token::RightSquareBracket) => depth -= 1,
+            Some(Token::Comma | Token::Semicolon) if depth == 0 => {
+                lines.push(start_line(line.indent + 1, &line.items[element_start..=i]));
+                element_start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    if element_start < close {
+        lines.push(start_line(line.indent + 1, &line.items[element_start..close]));
+    }
+    lines.push(start_line(line.indent, &line.items[close..]));
+    Some(lines)
+}
+
+// Rewrites a short string literal with the preferred quotes, unless that would need more escapes.
+// Long strings are left alone.
+fn requote(text: &[u8], style: QuoteStyle) -> Vec<u8> {
+    let target = match style {
+        QuoteStyle::Double => b'"',
+        QuoteStyle::Single => b'\'',
+        QuoteStyle::Preserve => return text.to_vec(),
+    };
+    let quote = match text.first() {
+        Some(&c) if (c == b'"' || c == b'\'') && c != target => c,
+        _ => return text.to_vec(),
+    };
+    let mut ret = vec![target];
+    let mut escaped = false;
+    for &c in text[1..text.len() - 1].iter() {
+        if escaped {
+            // The old quote no longer needs its escape
+            if c != quote {
+                ret.push(b'\\');
+            }
+            ret.push(c);
+            escaped = false;
+        } else if c == b'\\' {
+            escaped = true;
+        } else if c == target {
+            return text.to_vec();
+        } else {
+            ret.push(c);
+        }
+    }
+    ret.push(target);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fmt(src: &[u8]) -> Vec<u8> {
+        format(src, &FormatOptions::default()).ok().expect("source should format")
+    }
+
+    const SAMPLES: [&[u8]; 5] = [
+        b"local  x=1\nif x>0 then print( 'a' ) elseif x then\nx=-x end\n",
+        b"-- comment\n\n\n\nlocal t={1,2;3}\nfunction t.f(a,b) return a..b end\n",
+        b"#!/usr/bin/env lua\nlocal s = [[\n  keep ]] --[==[ block\ncomment ]==]\nwhile true do break end\n",
+        b"x = some_function_with_a_long_name(first_argument, second_argument, third_argument, fourth_argument)\n",
+        b"local a <const>, b <close> = 1, nil\n::top:: goto top\n",
+    ];
+
+    #[test]
+    fn formats_source() {
+        assert_eq!(fmt(SAMPLES[0]), b"local x = 1\nif x > 0 then print(\"a\") elseif x then\n    x = -x end\n");
+        assert_eq!(fmt(b"local t={ }\nt [1]=#t\n"), b"local t = {}\nt[1] = #t\n");
+    }
+
+    #[test]
+    fn is_idempotent() {
+        for src in SAMPLES {
+            let once = fmt(src);
+            assert_eq!(fmt(&once), once, "{}", String::from_utf8_lossy(src));
+        }
+    }
+
+    #[test]
+    fn keeps_bytes_that_are_not_utf8() {
+        assert_eq!(fmt(b"-- caf\xe9\nx='\xff\xfe'  --[[ \x80 ]]\n"), b"-- caf\xe9\nx = \"\xff\xfe\" --[[ \x80 ]]\n");
+        let formatted = b"-- caf\xe9\nprint(\"\xe9\")\n";
+        assert_eq!(fmt(formatted), formatted);
+    }
+
+    #[test]
+    fn rejects_invalid_source() {
+        assert!(matches!(format("x = = 1", &FormatOptions::default()), Err(FormatError::Parse(_))));
+        assert!(matches!(format("x = 'a", &FormatOptions::default()), Err(FormatError::Lex(_))));
+    }
+}
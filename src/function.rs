@@ -1,6 +1,12 @@
-use std::{hash::Hash, rc::Rc, borrow::{Borrow, BorrowMut}, collections::VecDeque, cell::RefCell};
+use std::{hash::Hash, rc::Rc, borrow::{Borrow, BorrowMut}, cell::RefCell};
 
-use crate::{stmt::Stmt, expr::Expr, table::UserTable};
+use crate::{stmt::Stmt, expr::Expr, values::Value, resolver::FunctionInfo};
+
+// What a function value takes from the place it was created: the variables it captures
+#[derive(Clone, Default)]
+pub struct Closure {
+    pub upvalues: Vec<Rc<RefCell<Value>>>,
+}
 
 #[derive(Clone)]
 pub struct Function {
@@ -18,8 +24,8 @@ impl PartialEq for Function {
 impl Eq for Function {}
 
 impl Function {
-    pub fn new(body: Box<Stmt>, params: Vec<Expr>, name: Option<String>, closure: VecDeque<UserTable>) -> Self {
-        Self { fi: Box::new(FunctionImpl::new(body, params, name, closure)) }
+    pub fn new(body: Box<Stmt>, params: Vec<Expr>, name: Option<String>) -> Self {
+        Self { fi: Box::new(FunctionImpl::new(body, params, name, FunctionInfo::default(), Closure::default())) }
     }
 
     pub fn get_name(&self) -> Option<String> {
@@ -34,11 +40,28 @@ impl Function {
         self.fi.body.borrow()
     }
 
-    pub fn get_closure(&self) -> VecDeque<UserTable> {
-        self.fi.as_ref().borrow().closure.clone()
+    pub fn get_params_mut(&mut self) -> &mut Vec<Expr> {
+        &mut self.fi.params
+    }
+
+    pub fn get_body_mut(&mut self) -> &mut Stmt {
+        &mut self.fi.body
+    }
+
+    // What the resolver found out about the function: the variables it captures and the slots a call needs
+    pub fn get_info(&self) -> &FunctionInfo {
+        &self.fi.info
+    }
+
+    pub fn set_info(&mut self, info: FunctionInfo) {
+        self.fi.info = info;
+    }
+
+    pub fn get_closure(&self) -> &Closure {
+        &self.fi.closure
     }
 
-    pub fn set_closure(&mut self, new_closure: VecDeque<UserTable>) {
+    pub fn set_closure(&mut self, new_closure: Closure) {
         let mut_fi: &mut FunctionImpl = self.fi.borrow_mut();
         mut_fi.closure = new_closure;
     }
@@ -48,7 +71,8 @@ pub struct FunctionImpl {
     pub body: Box<Stmt>,
     pub params: Vec<Expr>,
     pub name: Option<String>,
-    pub closure: VecDeque<UserTable>,
+    pub info: FunctionInfo,
+    pub closure: Closure,
 }
 
 impl Hash for Function {
@@ -60,17 +84,18 @@ impl Hash for Function {
 
 impl Clone for FunctionImpl {
     fn clone(&self) -> Self {
-        return Self { body: self.body.clone(), params: self.params.clone(), name: self.name.clone(), closure: self.closure.clone() }
+        return Self { body: self.body.clone(), params: self.params.clone(), name: self.name.clone(), info: self.info.clone(), closure: self.closure.clone() }
     }
 }
 
 impl FunctionImpl {
 
-    pub fn new(body: Box<Stmt>, params: Vec<Expr>, name: Option<String>, closure: VecDeque<UserTable>) -> Self {
+    pub fn new(body: Box<Stmt>, params: Vec<Expr>, name: Option<String>, info: FunctionInfo, closure: Closure) -> Self {
         let this = Self {
             body,
             params,
             name,
+            info,
             closure
         };
         this
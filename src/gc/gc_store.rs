@@ -1,9 +1,10 @@
 use std::borrow::BorrowMut;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 use crate::gc::gc_key::GcKey;
 use crate::gc::gc_values::GcValue;
 use crate::interpreter::Interpreter;
-use crate::table::Table;
 use crate::values::Value;
 
 pub struct GcStore {
@@ -28,74 +29,37 @@ impl GcStore {
         self.store.get_mut(&key)
     }
 
-    pub fn collect_garbage(&mut self, stack: &std::collections::VecDeque<crate::table::UserTable>) {
-        let mut marked_gc_keys = vec![];
-        for s in stack.iter() {
-            println!("collect_garbage root iter");
-            for (key, value) in s.table.as_ref().borrow().iter() {
-                match key {
-                    &Value::Table(ref gc_key) => {
-                        println!("Key is table");
-                        let gc_value = self.get_value(gc_key).unwrap();
-                        if let GcValue::Table(gct) = gc_value {
-                            marked_gc_keys.push(gc_key.clone());
-                            let newly_marked_keys = &mut self.collect_garbage_from(gct, &mut marked_gc_keys);
-                            marked_gc_keys.append(newly_marked_keys);
-                        }
-                    },
-                    _ => {}
-                };
-                match value {
-                    &Value::Table(ref gc_key) => {
-                        println!("Value is table");
-                        let gc_value = self.get_value(gc_key).unwrap();
-                        if let GcValue::Table(gct) = gc_value {
-                            marked_gc_keys.push(gc_key.clone());
-                            let newly_marked_keys = &mut self.collect_garbage_from(gct, &mut marked_gc_keys);
-                            marked_gc_keys.append(newly_marked_keys);
-                        }
-                    },
-                    _ => {}
-                }
-            }
-        }
-        let len_before_collect = self.store.len();
-        println!("We found {} garbage collectable objects through marking, a total of {} have been allocated", marked_gc_keys.len(), self.store.len());
-        self.store.retain(|key, value| {
-            marked_gc_keys.contains(key)
-        });
-        println!("Removed {} element(s)", len_before_collect - self.store.len());
-    }
-
-    fn collect_garbage_from(&self, table: &Table, visited_list: &mut Vec<GcKey>) -> Vec<GcKey> {
-        let mut res = vec![];
-        for (key, value) in table.iter() {
-            match key {
-                &Value::Table(ref gck) => {
-                    if visited_list.contains(gck) {
-                        return res;
+    // Marks everything reachable from `roots` and frees the rest. Tables are followed through their keys and
+    // values, the metatable included, and functions through the variables they captured
+    pub fn collect_garbage(&mut self, roots: &[Value]) {
+        let mut marked_gc_keys: HashSet<GcKey> = HashSet::new();
+        let mut visited_cells: HashSet<*const RefCell<Value>> = HashSet::new();
+        let mut pending: Vec<Value> = roots.to_vec();
+        while let Some(value) = pending.pop() {
+            match value {
+                Value::Table(gc_key) => {
+                    if !marked_gc_keys.insert(gc_key.clone()) {
+                        continue;
                     }
-                    if let Some(GcValue::Table(child_table)) = self.get_value(&gck) {
-                        res.append(&mut self.collect_garbage_from(child_table, visited_list));
+                    if let Some(GcValue::Table(table)) = self.get_value(&gc_key) {
+                        pending.extend(table.iter().flat_map(|(k, v)| [k.clone(), v.clone()]));
                     }
-                    res.push(gck.clone());
                 },
-                _ => {}
-            };
-            match value {
-                &Value::Table(ref gck) => {
-                    if visited_list.contains(gck) {
-                        return res;
-                    }
-                    if let Some(GcValue::Table(child_table)) = self.get_value(&gck) {
-                        res.append(&mut self.collect_garbage_from(child_table, visited_list));
+                Value::FunctionDef(function) => {
+                    for cell in function.get_closure().upvalues.iter() {
+                        // A cell can hold the function itself, as with recursive local functions
+                        if visited_cells.insert(Rc::as_ptr(cell)) {
+                            pending.push(cell.as_ref().borrow().clone());
+                        }
                     }
-                    res.push(gck.clone());
                 },
-                _ => {}
-            };
-
+                Value::ValList(values) => pending.extend(values),
+                _ => {},
+            }
         }
-        return res;
+        let len_before_collect = self.store.len();
+        println!("We found {} garbage collectable objects through marking, a total of {} have been allocated", marked_gc_keys.len(), self.store.len());
+        self.store.retain(|key, _| marked_gc_keys.contains(key));
+        println!("Removed {} element(s)", len_before_collect - self.store.len());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,189 @@
+use crate::lexer::{Lexer, LexError, RESERVED_WORDS};
+use crate::tokens::{Token, TriviaKind};
+use crate::values::Value;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum HighlightFormat {
+    Ansi,
+    Html,
+}
+
+impl HighlightFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ansi" => Some(HighlightFormat::Ansi),
+            "html" => Some(HighlightFormat::Html),
+            _ => None
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum Category {
+    Keyword,
+    String,
+    Number,
+    Identifier,
+    Operator,
+    Comment,
+    Plain,
+}
+
+impl Category {
+    fn ansi_code(&self) -> Option<&'static str> {
+        match self {
+            Category::Keyword => Some("1;35"),
+            Category::String => Some("32"),
+            Category::Number => Some("33"),
+            Category::Identifier => Some("34"),
+            Category::Operator => Some("36"),
+            Category::Comment => Some("90"),
+            Category::Plain => None,
+        }
+    }
+
+    fn html_class(&self) -> Option<&'static str> {
+        match self {
+            Category::Keyword => Some("keyword"),
+            Category::String => Some("string"),
+            Category::Number => Some("number"),
+            Category::Identifier => Some("identifier"),
+            Category::Operator => Some("operator"),
+            Category::Comment => Some("comment"),
+            Category::Plain => None,
+        }
+    }
+}
+
+// Colourises Lua source without executing it. Comments and whitespace are kept as they are in `src`,
+// bytes that are not valid UTF-8 included.
+pub fn highlight<S: AsRef<[u8]> + ?Sized>(src: &S, format: HighlightFormat) -> Result<Vec<u8>, LexError> {
+    let tokens = Lexer::tokenize_lossless(src)?;
+    let mut ret = vec![];
+    if format == HighlightFormat::Html {
+        ret.extend_from_slice(b"<pre class=\"redmoon\">");
+    }
+    for t in tokens.tokens.iter() {
+        for trivia in t.leading_trivia.iter() {
+            emit(&mut ret, trivia_category(trivia.kind), &trivia.text, format);
+        }
+        emit(&mut ret, token_category(&t.token.token, &t.text), &t.text, format);
+    }
+    for trivia in tokens.trailing_trivia.iter() {
+        emit(&mut ret, trivia_category(trivia.kind), &trivia.text, format);
+    }
+    if format == HighlightFormat::Html {
+        ret.extend_from_slice(b"</pre>\n");
+    }
+    Ok(ret)
+}
+
+fn trivia_category(kind: TriviaKind) -> Category {
+    match kind {
+        TriviaKind::LineComment | TriviaKind::BlockComment | TriviaKind::Shebang => Category::Comment,
+        TriviaKind::Whitespace | TriviaKind::Newline => Category::Plain,
+    }
+}
+
+fn token_category(token: &Token, text: &[u8]) -> Category {
+    // `true`, `false` and `nil` are lexed as literals but read as keywords
+    if std::str::from_utf8(text).is_ok_and(|text| RESERVED_WORDS.binary_search(&text).is_ok()) {
+        return Category::Keyword;
+    }
+    match token {
+        Token::Literal(Value::String(_)) => Category::String,
+        Token::Literal(_) => Category::Number,
+        Token::Identifier(_) => Category::Identifier,
+        _ => Category::Operator,
+    }
+}
+
+fn emit(out: &mut Vec<u8>, category: Category, text: &[u8], format: HighlightFormat) {
+    match format {
+        HighlightFormat::Ansi => {
+            if let Some(code) = category.ansi_code() {
+                // Reset at every line break so a multi-line comment or string does not bleed into a pager's next screen
+                for (i, line) in text.split(|b| *b == b'\n').enumerate() {
+                    if i > 0 {
+                        out.push(b'\n');
+                    }
+                    if !line.is_empty() {
+                        out.extend_from_slice(format!("\x1b[{code}m").as_bytes());
+                        out.extend_from_slice(line);
+                        out.extend_from_slice(b"\x1b[0m");
+                    }
+                }
+            } else {
+                out.extend_from_slice(text);
+            }
+        },
+        HighlightFormat::Html => {
+            if let Some(class) = category.html_class() {
+                out.extend_from_slice(format!("<span class=\"{class}\">").as_bytes());
+                escape_html(text, out);
+                out.extend_from_slice(b"</span>");
+            } else {
+                escape_html(text, out);
+            }
+        }
+    }
+}
+
+fn escape_html(text: &[u8], out: &mut Vec<u8>) {
+    for b in text.iter() {
+        match b {
+            b'&' => out.extend_from_slice(b"&amp;"),
+            b'<' => out.extend_from_slice(b"&lt;"),
+            b'>' => out.extend_from_slice(b"&gt;"),
+            b'"' => out.extend_from_slice(b"&quot;"),
+            _ => out.push(*b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(src: &str, format: HighlightFormat) -> String {
+        String::from_utf8(highlight(src, format).expect("source should lex")).expect("output should be UTF-8")
+    }
+
+    #[test]
+    fn ansi_output() {
+        assert_eq!(render("local x = 1 -- one\n", HighlightFormat::Ansi),
+            "\x1b[1;35mlocal\x1b[0m \x1b[34mx\x1b[0m \x1b[36m=\x1b[0m \x1b[33m1\x1b[0m \x1b[90m-- one\x1b[0m\n");
+        assert_eq!(render("print(nil, 'a')", HighlightFormat::Ansi),
+            "\x1b[34mprint\x1b[0m\x1b[36m(\x1b[0m\x1b[1;35mnil\x1b[0m\x1b[36m,\x1b[0m \x1b[32m'a'\x1b[0m\x1b[36m)\x1b[0m");
+    }
+
+    #[test]
+    fn ansi_resets_at_line_breaks() {
+        assert_eq!(render("--[[a\nb]]", HighlightFormat::Ansi), "\x1b[90m--[[a\x1b[0m\n\x1b[90mb]]\x1b[0m");
+    }
+
+    #[test]
+    fn html_output() {
+        assert_eq!(render("x = 1", HighlightFormat::Html),
+            "<pre class=\"redmoon\"><span class=\"identifier\">x</span> <span class=\"operator\">=</span> <span class=\"number\">1</span></pre>\n");
+    }
+
+    #[test]
+    fn html_escapes_strings_and_comments() {
+        assert_eq!(render("s = \"<a href='x'>&</a>\"", HighlightFormat::Html),
+            "<pre class=\"redmoon\"><span class=\"identifier\">s</span> <span class=\"operator\">=</span> \
+            <span class=\"string\">&quot;&lt;a href='x'&gt;&amp;&lt;/a&gt;&quot;</span></pre>\n");
+        assert_eq!(render("-- a < b && \"c\" > d", HighlightFormat::Html),
+            "<pre class=\"redmoon\"><span class=\"comment\">-- a &lt; b &amp;&amp; &quot;c&quot; &gt; d</span></pre>\n");
+        assert_eq!(render("x = a<b", HighlightFormat::Html),
+            "<pre class=\"redmoon\"><span class=\"identifier\">x</span> <span class=\"operator\">=</span> \
+            <span class=\"identifier\">a</span><span class=\"operator\">&lt;</span><span class=\"identifier\">b</span></pre>\n");
+    }
+    #[test]
+    fn bytes_pass_through() {
+        let out = highlight(b"-- caf\xe9\nx = '\xff'", HighlightFormat::Ansi).expect("source should lex");
+        assert_eq!(out, b"\x1b[90m-- caf\xe9\x1b[0m\n\x1b[34mx\x1b[0m \x1b[36m=\x1b[0m \x1b[32m'\xff'\x1b[0m");
+        let out = highlight(b"-- <\xe9>", HighlightFormat::Html).expect("source should lex");
+        assert_eq!(out, b"<pre class=\"redmoon\"><span class=\"comment\">-- &lt;\xe9&gt;</span></pre>\n");
+    }
+}
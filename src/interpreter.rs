@@ -1,5 +1,6 @@
-use crate::{Token, Expr, Stmt, Value, table::{UserTable, Table}, native_function::NativeFunction, function::Function, gc::gc_store::GcStore, gc::{gc_values::GcValue, gc_key::GcKey}};
-use std::{collections::{VecDeque}, borrow::{BorrowMut}};
+use crate::{Token, Expr, Stmt, Value, expr::{ExprKind, Field}, stmt::{StmtKind, LocalAttrib}, table::{UserTable, Table}, native_function::NativeFunction, function::{Function, Closure}, arith, gc::gc_store::GcStore, gc::{gc_values::GcValue, gc_key::GcKey}, span::Span};
+use crate::resolver::{FunctionInfo, VarKind};
+use std::{rc::Rc, cell::RefCell, cmp::Ordering};
 use ordered_float::OrderedFloat;
 #[cfg(target_family = "wasm")]
 use wasm_bindgen::{JsValue, prelude::*};
@@ -10,10 +11,38 @@ extern "C" {
     fn append_to_output(str: JsValue);
 }
 
+// An assignment target whose table and key have already been evaluated
+enum AssignTarget {
+    Name(VarKind, String),
+    Field(Value, Value, Span),
+}
+
+// Storage of one variable, shared with the closures that capture it
+type Cell = Rc<RefCell<Value>>;
+
+// The variables of a running function, laid out as the resolver placed them
+struct Frame {
+    slots: Vec<Cell>,
+    upvalues: Vec<Cell>,
+    varargs: Vec<Value>,
+}
+
+impl Frame {
+    fn new(slots: usize, upvalues: Vec<Cell>) -> Self {
+        let slots = (0..slots).map(|_| Rc::new(RefCell::new(Value::Nil))).collect();
+        Self { slots, upvalues, varargs: vec![] }
+    }
+}
+
 pub struct Interpreter {
     _G: UserTable,
-    stack: VecDeque<UserTable>,
-    gc: GcStore
+    // One frame per function call in progress, the innermost last
+    frames: Vec<Frame>,
+    gc: GcStore,
+    // Values of `<close>` locals still in scope, with their `__close` metamethod
+    to_be_closed: Vec<(Value, Value)>,
+    // Whether `exec_repl` runs the optimizer before executing, turned off when debugging
+    optimize: bool,
 }
 
 impl Interpreter {
@@ -28,7 +57,7 @@ impl Interpreter {
                 }
                 println!("Native print: {v}");
             }
-            None
+            Ok(None)
         })));
         let setmetatable = Value::NativeFunctionDef(NativeFunction::new(Box::new(|interp, args| {
             if args.len() < 2 {
@@ -58,29 +87,28 @@ impl Interpreter {
                     ()
                 }
             }
-            Some(args[0].clone())
+            Ok(Some(args[0].clone()))
         })));
 
         let getmetatable = Value::NativeFunctionDef(NativeFunction::new(Box::new(|interp, args| {
             if let Some(Value::Table(t)) = args.get(0) {
                 if let Some(GcValue::Table(gc_table)) = interp.gc.get_value(t) {
-                    return gc_table.get(&Value::MetaKey).cloned();
+                    return Ok(gc_table.get(&Value::MetaKey).cloned());
                 }
             }
-            return None;
+            return Ok(None);
         })));
 
         let collectgarbage = Value::NativeFunctionDef(NativeFunction::new(Box::new(|interp, args| {
-            let mut stack = interp.get_stack().clone();
-            stack.push_front(interp._G.clone());
-            interp.gc.collect_garbage(&stack);
-            return Some(Value::Nil);
+            let roots = interp.gc_roots();
+            interp.gc.collect_garbage(&roots);
+            return Ok(Some(Value::Nil));
         })));
 
         let assert = Value::NativeFunctionDef(NativeFunction::new(Box::new(|interp, args| {
             if let Some(v) = args.get(0) {
                 if interp.is_truthy(v) {
-                    return Some(Value::ValList(args.to_vec()));
+                    return Ok(Some(Value::ValList(args.to_vec())));
                 } else {
                     let default_error_msg = Value::String("Assertion failed!".into());
                     let error_msg = if let Some(em) = args.get(1) {
@@ -89,10 +117,54 @@ impl Interpreter {
                         &default_error_msg
                     };
 
-                    panic!("{}", error_msg);
+                    return Err(error_msg.to_string());
                 }
             }
-            panic!("assert(): Requires at least 1 argument");
+            Err("assert(): Requires at least 1 argument".into())
+        })));
+        let next = Value::NativeFunctionDef(NativeFunction::new(Box::new(|interp, args| {
+            let Some(Value::Table(t)) = args.get(0) else {
+                return Err("bad argument #1 to 'next' (table expected)".into());
+            };
+            match interp.table_next(t, args.get(1).unwrap_or(&Value::Nil))? {
+                Some((k, v)) => Ok(Some(Value::ValList(vec![k, v]))),
+                None => Ok(Some(Value::Nil)),
+            }
+        })));
+
+        let pairs_next = next.clone();
+        let pairs = Value::NativeFunctionDef(NativeFunction::new(Box::new(move |interp, args| {
+            let Some(Value::Table(t)) = args.get(0) else {
+                return Err("bad argument #1 to 'pairs' (table expected)".into());
+            };
+            let table = args[0].clone();
+            let meta_pairs = interp.get_table(t).and_then(Self::get_metatable)
+                .and_then(|m| interp.get_table(&m).and_then(|m| m.get(&Value::String("__pairs".into())).cloned()));
+            if let Some(meta_pairs) = meta_pairs {
+                let results = match interp.call_value(meta_pairs, vec![table], Span::default())? {
+                    Value::ValList(vl) => vl,
+                    v => vec![v],
+                };
+                return Ok(Some(Value::ValList(results.into_iter().chain(std::iter::repeat(Value::Nil)).take(3).collect())));
+            }
+            Ok(Some(Value::ValList(vec![pairs_next.clone(), table, Value::Nil])))
+        })));
+
+        let ipairs_next = Value::NativeFunctionDef(NativeFunction::new(Box::new(|interp, args| {
+            let (Some(Value::Table(t)), Some(Value::Integer(i))) = (args.get(0), args.get(1)) else {
+                return Ok(Some(Value::Nil));
+            };
+            let i = i.wrapping_add(1);
+            match interp.get_table(t).and_then(|table| table.get(&Value::Integer(i))) {
+                Some(Value::Nil) | None => Ok(Some(Value::Nil)),
+                Some(v) => Ok(Some(Value::ValList(vec![Value::Integer(i), v.clone()]))),
+            }
+        })));
+        let ipairs = Value::NativeFunctionDef(NativeFunction::new(Box::new(move |_, args| {
+            let Some(table @ Value::Table(_)) = args.get(0) else {
+                return Err("bad argument #1 to 'ipairs' (table expected)".into());
+            };
+            Ok(Some(Value::ValList(vec![ipairs_next.clone(), table.clone(), Value::Integer(0)])))
         })));
         let mut _G = UserTable::new();
         _G.table.as_ref().borrow_mut().insert(Value::String("print".into()), print);
@@ -100,57 +172,94 @@ impl Interpreter {
         _G.table.as_ref().borrow_mut().insert(Value::String("getmetatable".into()), getmetatable);
         _G.table.as_ref().borrow_mut().insert(Value::String("collectgarbage".into()), collectgarbage);
         _G.table.as_ref().borrow_mut().insert(Value::String("assert".into()), assert);
-        Self { _G, stack: VecDeque::new(), gc: GcStore::new() }
+        _G.table.as_ref().borrow_mut().insert(Value::String("next".into()), next);
+        _G.table.as_ref().borrow_mut().insert(Value::String("pairs".into()), pairs);
+        _G.table.as_ref().borrow_mut().insert(Value::String("ipairs".into()), ipairs);
+        Self { _G, frames: vec![], gc: GcStore::new(), to_be_closed: vec![], optimize: true }
     }
 
-    fn push_env(&mut self) {
-        self.stack.push_back(UserTable::new());
+    fn frame(&self) -> &Frame {
+        self.frames.last().expect("no function is running")
     }
 
-    fn push_custom_env(&mut self, env: UserTable) {
-        self.stack.push_back(env);
+    fn get_var(&self, kind: VarKind, name: &str) -> Value {
+        match kind {
+            VarKind::Local(slot) => self.frame().slots[slot].as_ref().borrow().clone(),
+            VarKind::Upvalue(index) => self.frame().upvalues[index].as_ref().borrow().clone(),
+            VarKind::Global => self._G.table.as_ref().borrow().get(&Value::String(name.into())).cloned().unwrap_or(Value::Nil),
+        }
     }
 
-    fn pop_env(&mut self) {
-        self.stack.pop_back();
+    fn set_var(&mut self, kind: VarKind, name: &str, value: Value) {
+        match kind {
+            VarKind::Local(slot) => *self.frame().slots[slot].as_ref().borrow_mut() = value,
+            VarKind::Upvalue(index) => *self.frame().upvalues[index].as_ref().borrow_mut() = value,
+            VarKind::Global => {
+                self._G.table.as_ref().borrow_mut().insert(Value::String(name.into()), value);
+            },
+        }
     }
 
-    fn get_current_stack_env(&mut self) -> &mut UserTable {
-        if let Some(env) = self.stack.back_mut() {
-            return env;
-        } else {
-            return self._G.borrow_mut();
+    // Starts a new variable in the slot of the declaration `var`. Closures created before keep the old one
+    fn declare(&mut self, var: &Expr, value: Value) {
+        let ExprKind::Var(_, VarKind::Local(slot)) = var.kind else {
+            panic!("Internal error: declaration at {} has no slot", var.span);
+        };
+        if let Some(frame) = self.frames.last_mut() {
+            frame.slots[slot] = Rc::new(RefCell::new(value));
         }
-
     }
 
-    fn find_var(&self, name: &String) -> Option<Value> {
-        let val_key = Value::String(name.clone());
-        for t in self.stack.iter().rev() {
-            if let Some(ret) = t.table.as_ref().borrow().get(&val_key) {
-                return Some(ret.clone());
-            }
+    // Values the garbage collector marks from: the globals and the variables of every running function
+    fn gc_roots(&self) -> Vec<Value> {
+        let mut roots: Vec<Value> = self._G.table.as_ref().borrow().iter().flat_map(|(k, v)| [k.clone(), v.clone()]).collect();
+        for frame in self.frames.iter() {
+            roots.extend(frame.slots.iter().chain(frame.upvalues.iter()).map(|cell| cell.as_ref().borrow().clone()));
+            roots.extend(frame.varargs.iter().cloned());
         }
-        return self._G.table.as_ref().borrow_mut().get(&val_key).cloned();
+        roots
     }
 
     fn stringify(&self, v: Value) -> Result<Value, String> {
         match v {
             Value::String(s) => return Ok(Value::String(s)),
-            Value::Number(n) => return Ok(Value::String(format!("{}", n))),
+            Value::Number(n) => return Ok(Value::String(crate::values::fmt_float(n.0).into())),
+            Value::Integer(i) => return Ok(Value::String(format!("{}", i).into())),
             _ => return Err("Cannot stringify value".into())
         }
     }
 
-    fn are_both_values_numbers(v1: &Value, v2: &Value) -> Option<(ordered_float::OrderedFloat<f32>, ordered_float::OrderedFloat<f32>)> {
-        if let Value::Number(n1) = v1 {
-            if let Value::Number(n2) = v2 {
-                return Some((n1.clone(), n2.clone()));
+    fn to_float(v: &Value) -> Option<OrderedFloat<f64>> {
+        arith::to_float(v).map(OrderedFloat)
+    }
+
+    // Converts both operands to floats if they are numbers of any subtype
+    fn are_both_values_numbers(v1: &Value, v2: &Value) -> Option<(OrderedFloat<f64>, OrderedFloat<f64>)> {
+        if let Some(n1) = Self::to_float(v1) {
+            if let Some(n2) = Self::to_float(v2) {
+                return Some((n1, n2));
             }
         }
         return None;
     }
 
+    fn are_both_values_integers(v1: &Value, v2: &Value) -> Option<(i64, i64)> {
+        if let (Value::Integer(i1), Value::Integer(i2)) = (v1, v2) {
+            return Some((*i1, *i2));
+        }
+        return None;
+    }
+
+    // Floats with an exact integer value index the same table slot as that integer
+    fn normalize_key(key: Value) -> Value {
+        if let Value::Number(_) = key {
+            if let Some(i) = arith::to_integer(&key) {
+                return Value::Integer(i);
+            }
+        }
+        key
+    }
+
     fn which_value_is_table<'a>(&self, v1: &'a Value, v2: &'a Value) -> Option<&'a Value> {
         if let Value::Table(table1) = v1 {
             if let Some(GcValue::Table(_)) = self.gc.get_value(table1) {
@@ -180,117 +289,83 @@ impl Interpreter {
         return None;
     }
 
-    fn add_vals<'a>(&mut self, t1: &'a Value, t2: &'a Value) -> Value {
-        if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
-            return Value::Number(n1 + n2);
-        } else if let Some(Value::Table(table)) = self.which_value_is_table(&t1, &t2) {
+    // Calls the metamethod for `event` of whichever operand is a table, if there is one
+    fn binary_metamethod(&mut self, t1: &Value, t2: &Value, event: &str) -> Result<Value, String> {
+        if let Some(Value::Table(table)) = self.which_value_is_table(t1, t2) {
             if let Some(table) = self.get_table(table) {
                 if let Some(key) = Self::get_metatable(table) {
                     if let Some(meta_table) = self.get_table(&key) {
-                        if let Some(Value::FunctionDef(fd)) = meta_table.get(&Value::String("__add".into())) {
-                            self.call_fn(&fd.clone(), &vec![Expr::Literal(t1.clone()), Expr::Literal(t2.clone())]);
+                        if let Some(Value::FunctionDef(fd)) = meta_table.get(&Value::String(event.into())) {
+                            // Only the first result of a metamethod is used
+                            return Ok(match self.call_fn(&fd.clone(), vec![t1.clone(), t2.clone()])? {
+                                Value::ValList(vl) => vl.into_iter().next().unwrap_or(Value::Nil),
+                                v => v,
+                            });
                         }
-                    }    
+                    }
                 }
             }
         }
-        return Value::Nil;
+        return Ok(Value::Nil);
+    }
+
+    fn add_vals<'a>(&mut self, t1: &'a Value, t2: &'a Value) -> Result<Value, String> {
+        if let Some((i1, i2)) = Self::are_both_values_integers(&t1, &t2) {
+            return Ok(Value::Integer(i1.wrapping_add(i2)));
+        } else if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
+            return Ok(Value::Number(n1 + n2));
+        }
+        return self.binary_metamethod(&t1, &t2, "__add");
     }
     
-    fn subtract_vals(&mut self, t1: Value, t2: Value) -> Value {
-        if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
-            return Value::Number(n1 - n2);
-        } else if let Some(Value::Table(table)) = self.which_value_is_table(&t1, &t2) {
-            if let Some(table) = self.get_table(table) {
-                if let Some(key) = Self::get_metatable(table) {
-                    if let Some(meta_table) = self.get_table(&key) {
-                        if let Some(Value::FunctionDef(fd)) = meta_table.get(&Value::String("__sub".into())) {
-                            self.call_fn(&fd.clone(), &vec![Expr::Literal(t1.clone()), Expr::Literal(t2.clone())]);
-                        }
-                    }    
-                }
-            }
+    fn subtract_vals(&mut self, t1: Value, t2: Value) -> Result<Value, String> {
+        if let Some((i1, i2)) = Self::are_both_values_integers(&t1, &t2) {
+            return Ok(Value::Integer(i1.wrapping_sub(i2)));
+        } else if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
+            return Ok(Value::Number(n1 - n2));
         }
-        return Value::Nil;
+        return self.binary_metamethod(&t1, &t2, "__sub");
     }
     
-    fn multiply_vals(&mut self, t1: Value, t2: Value) -> Value {
-        if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
-            return Value::Number(n1 * n2);
-        } else if let Some(Value::Table(table)) = self.which_value_is_table(&t1, &t2) {
-            if let Some(table) = self.get_table(table) {
-                if let Some(key) = Self::get_metatable(table) {
-                    if let Some(meta_table) = self.get_table(&key) {
-                        if let Some(Value::FunctionDef(fd)) = meta_table.get(&Value::String("__mul".into())) {
-                            self.call_fn(&fd.clone(), &vec![Expr::Literal(t1.clone()), Expr::Literal(t2.clone())]);
-                        }
-                    }    
-                }
-            }
+    fn multiply_vals(&mut self, t1: Value, t2: Value) -> Result<Value, String> {
+        if let Some((i1, i2)) = Self::are_both_values_integers(&t1, &t2) {
+            return Ok(Value::Integer(i1.wrapping_mul(i2)));
+        } else if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
+            return Ok(Value::Number(n1 * n2));
         }
-        return Value::Nil;
+        return self.binary_metamethod(&t1, &t2, "__mul");
     }
     
-    fn divide_vals(&mut self, t1: Value, t2: Value) -> Value {
+    fn divide_vals(&mut self, t1: Value, t2: Value) -> Result<Value, String> {
         if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
-            return Value::Number(n1 / n2);
-        } else if let Some(Value::Table(table)) = self.which_value_is_table(&t1, &t2) {
-            if let Some(table) = self.get_table(table) {
-                if let Some(key) = Self::get_metatable(table) {
-                    if let Some(meta_table) = self.get_table(&key) {
-                        if let Some(Value::FunctionDef(fd)) = meta_table.get(&Value::String("__div".into())) {
-                            self.call_fn(&fd.clone(), &vec![Expr::Literal(t1.clone()), Expr::Literal(t2.clone())]);
-                        }
-                    }    
-                }
-            }
+            return Ok(Value::Number(n1 / n2));
         }
-        return Value::Nil;
+        return self.binary_metamethod(&t1, &t2, "__div");
     }
     
-    fn less_than_or_equal(&mut self, t1: Value, t2: Value) -> Value {
-        if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
-            return Value::Boolean(n1 <= n2);
-        } else if let Some(Value::Table(table)) = self.which_value_is_table(&t1, &t2) {
-            if let Some(table) = self.get_table(table) {
-                if let Some(key) = Self::get_metatable(table) {
-                    if let Some(meta_table) = self.get_table(&key) {
-                        if let Some(Value::FunctionDef(fd)) = meta_table.get(&Value::String("__le".into())) {
-                            self.call_fn(&fd.clone(), &vec![Expr::Literal(t1.clone()), Expr::Literal(t2.clone())]);
-                        }
-                    }    
-                }
-            }
+    fn less_than_or_equal(&mut self, t1: Value, t2: Value) -> Result<Value, String> {
+        if let Some(ordering) = arith::compare_values(&t1, &t2) {
+            return Ok(Value::Boolean(ordering.is_some_and(|o| o != Ordering::Greater)));
         }
-        return Value::Nil;
+        return self.binary_metamethod(&t1, &t2, "__le");
     }
     
-    fn less_than(&mut self, t1: Value, t2: Value) -> Value {
-        if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
-            return Value::Boolean(n1 < n2);
-        } else if let Some(Value::Table(table)) = self.which_value_is_table(&t1, &t2) {
-            if let Some(table) = self.get_table(table) {
-                if let Some(key) = Self::get_metatable(table) {
-                    if let Some(meta_table) = self.get_table(&key) {
-                        if let Some(Value::FunctionDef(fd)) = meta_table.get(&Value::String("__lt".into())) {
-                            self.call_fn(&fd.clone(), &vec![Expr::Literal(t1.clone()), Expr::Literal(t2.clone())]);
-                        }
-                    }    
-                }
-            }
+    fn less_than(&mut self, t1: Value, t2: Value) -> Result<Value, String> {
+        if let Some(ordering) = arith::compare_values(&t1, &t2) {
+            return Ok(Value::Boolean(ordering == Some(Ordering::Less)));
         }
-        return Value::Nil;
+        return self.binary_metamethod(&t1, &t2, "__lt");
     }
 
     fn value_length(&self, v: &Value) -> Option<Value> {
         match v {
             Value::String(s) => {
-                Some(Value::Number(ordered_float::OrderedFloat(s.len() as f32)))
+                Some(Value::Integer(s.len() as i64))
             },
             Value::Table(key) => {
                 let table = self.gc.get_value(key);
                 if let Some(GcValue::Table(table)) = table {
-                    return Some(Value::Number(OrderedFloat(table.len() as f32)));
+                    return Some(Value::Integer(table.len() as i64));
                 }
                 None
             }
@@ -313,11 +388,8 @@ impl Interpreter {
         //     }
         // }
         match t1 {
-            Value::Number(n1) => {
-                match t2 {
-                    Value::Number(n2) => Value::Boolean(n1 == n2),
-                    _ => Value::Boolean(false)
-                }
+            Value::Number(_) | Value::Integer(_) => {
+                Value::Boolean(arith::compare_numbers(&t1, &t2) == Some(Some(Ordering::Equal)))
             },
             Value::Nil => {
                 match t2 {
@@ -358,236 +430,264 @@ impl Interpreter {
             Value::ValList(_list) => {
                 panic!("Cannot compare value lists to each other");
             }, 
-            Value::Interrupt => {
+            Value::Interrupt | Value::Goto(_) => {
                 panic!("Impossible value");
             },
             Value::MetaKey => {
                 panic!("Impossible value");
             },
-            Value::Varargs(_) => {
-                return if let Value::Varargs(_) = t2 {
-                    Value::Boolean(true)
-                } else {
-                    Value::Boolean(false)
-                }
-            },
-            Value::VarargsIdentifier => {
-                Value::Boolean(t2 == Value::VarargsIdentifier)
-            }
-
         }
     }
     
-    fn greater_than_or_equal(&mut self, t1: Value, t2: Value) -> Value {
-        // if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
-        //     return Value::Boolean(n1 >= n2);
-        // } else if let Some(table) = Self::which_value_is_table(&t1, &t2) {
-        //     let maybe_metamethod: Option<Function> = Self::get_metamethod(Self::get_metatable(table), "__ge".into());
-        //     if let Some(maybe_metamethod) = maybe_metamethod {
-        //         return self.call_fn(&maybe_metamethod, &vec![Expr::Literal(t1.clone()), Expr::Literal(t2.clone())]);
-        //     }
-        // }
-        return Value::Nil;
+    // `a >= b` is `b <= a`, so `__le` gets its operands swapped
+    fn greater_than_or_equal(&mut self, t1: Value, t2: Value) -> Result<Value, String> {
+        return self.less_than_or_equal(t2, t1);
     }
     
-    fn greater_than(&mut self, t1: Value, t2: Value) -> Value {
-        // if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
-        //     return Value::Boolean(n1 > n2);
-        // } else if let Some(table) = Self::which_value_is_table(&t1, &t2) {
-        //     let maybe_metamethod: Option<Function> = Self::get_metamethod(Self::get_metatable(table), "__gt".into());
-        //     if let Some(maybe_metamethod) = maybe_metamethod {
-        //         return self.call_fn(&maybe_metamethod, &vec![Expr::Literal(t1.clone()), Expr::Literal(t2.clone())]);
-        //     }
-        // }
-        return Value::Nil;
+    fn greater_than(&mut self, t1: Value, t2: Value) -> Result<Value, String> {
+        return self.less_than(t2, t1);
     }
 
-    fn modulo_vals(&mut self, t1: Value, t2: Value) -> Value {
+    fn pow_vals(&mut self, t1: Value, t2: Value) -> Result<Value, String> {
         if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
-            return Value::Number(n1 % n2);
-        } else if let Some(Value::Table(table)) = self.which_value_is_table(&t1, &t2) {
-            if let Some(table) = self.get_table(table) {
-                if let Some(key) = Self::get_metatable(table) {
-                    if let Some(meta_table) = self.get_table(&key) {
-                        if let Some(Value::FunctionDef(fd)) = meta_table.get(&Value::String("__mod".into())) {
-                            self.call_fn(&fd.clone(), &vec![Expr::Literal(t1.clone()), Expr::Literal(t2.clone())]);
-                        }
-                    }
-                }
+            return Ok(Value::Number(OrderedFloat(n1.powf(n2.0))));
+        }
+        return self.binary_metamethod(&t1, &t2, "__pow");
+    }
+
+    fn floor_divide_vals(&mut self, t1: Value, t2: Value) -> Result<Value, String> {
+        if let Some((i1, i2)) = Self::are_both_values_integers(&t1, &t2) {
+            if i2 == 0 {
+                return Err("attempt to perform 'n//0'".into());
             }
+            let q = i1.wrapping_div(i2);
+            return Ok(Value::Integer(if i1.wrapping_rem(i2) != 0 && (i1 ^ i2) < 0 { q - 1 } else { q }));
+        } else if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
+            return Ok(Value::Number(OrderedFloat((n1 / n2).floor())));
         }
-        return Value::Nil;
+        return self.binary_metamethod(&t1, &t2, "__idiv");
     }
 
-    fn is_truthy(&self, v: &Value) -> bool {
+    fn bitwise_vals(&mut self, t1: Value, op: &Token, t2: Value) -> Result<Value, String> {
+        if let (Some(i1), Some(i2)) = (arith::to_integer(&t1), arith::to_integer(&t2)) {
+            return Ok(Value::Integer(match op {
+                Token::Ampersand => i1 & i2,
+                Token::Pipe => i1 | i2,
+                Token::Tilde => i1 ^ i2,
+                Token::ShiftLeft => arith::shift_left(i1, i2),
+                _ => arith::shift_left(i1, i2.wrapping_neg()),
+            }));
+        } else if Self::are_both_values_numbers(&t1, &t2).is_some() {
+            return Err("number has no integer representation".into());
+        }
+        let event = match op {
+            Token::Ampersand => "__band",
+            Token::Pipe => "__bor",
+            Token::Tilde => "__bxor",
+            Token::ShiftLeft => "__shl",
+            _ => "__shr",
+        };
+        return self.binary_metamethod(&t1, &t2, event);
+    }
+
+    fn modulo_vals(&mut self, t1: Value, t2: Value) -> Result<Value, String> {
+        if let Some((i1, i2)) = Self::are_both_values_integers(&t1, &t2) {
+            if i2 == 0 {
+                return Err("attempt to perform 'n%0'".into());
+            }
+            let r = i1.wrapping_rem(i2);
+            return Ok(Value::Integer(if r != 0 && (r ^ i2) < 0 { r + i2 } else { r }));
+        } else if let Some((n1, n2)) = Self::are_both_values_numbers(&t1, &t2) {
+            let r = n1 % n2;
+            return Ok(Value::Number(if r != OrderedFloat(0.0) && (r < OrderedFloat(0.0)) != (n2 < OrderedFloat(0.0)) { r + n2 } else { r }));
+        }
+        return self.binary_metamethod(&t1, &t2, "__mod");
+    }
+
+    fn type_name(v: &Value) -> &'static str {
         match v {
-            Value::String(s) => !s.is_empty(),
-            Value::Nil => false,
-            Value::Boolean(b) => b.clone(),
-            _ => true
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Number(_) | Value::Integer(_) => "number",
+            Value::String(_) => "string",
+            Value::Table(_) => "table",
+            Value::FunctionDef(_) | Value::NativeFunctionDef(_) => "function",
+            _ => "internal",
         }
     }
 
-    fn eval_block(&mut self, stmts: &Vec<Stmt>) -> Result<Option<Expr>, String> {
-        for s in stmts {
+    fn is_truthy(&self, v: &Value) -> bool {
+        arith::is_truthy(v)
+    }
+
+    fn eval_block(&mut self, stmts: &[Stmt]) -> Result<Option<Expr>, String> {
+        let first_to_close = self.to_be_closed.len();
+        let res = self.run_block(stmts);
+        self.close_variables(first_to_close, res)
+    }
+
+    // Runs the statements of a block, leaving the `<close>` locals it declares to the caller
+    fn run_block(&mut self, stmts: &[Stmt]) -> Result<Option<Expr>, String> {
+        // Index of the statement that declared each `<close>` local of this block
+        let first_to_close = self.to_be_closed.len();
+        let mut declared_at: Vec<usize> = vec![];
+        let mut i = 0;
+        let res = loop {
+            let Some(s) = stmts.get(i) else {
+                break Ok(None);
+            };
             let res = self.eval_stmt(s);
-           if let Err(err) = res {
-            return Err(err);
-           } else if let Ok(None) = res {
+            declared_at.resize(self.to_be_closed.len() - first_to_close, i);
+            i += 1;
+            if let Ok(None) = res {
                 continue;
-           } else {
-            return res;
-           }
+            } else if let Ok(Some(Expr { kind: ExprKind::Literal(Value::Goto(label)), .. })) = &res {
+                // Labels of enclosing blocks are handled by whichever block defines them
+                let target = stmts.iter().position(|s| matches!(&s.kind, StmtKind::Label(l) if l == label));
+                if let Some(target) = target {
+                    // Jumping back above a `<close>` local leaves its scope
+                    let still_in_scope = declared_at.iter().take_while(|d| **d < target).count();
+                    if let Err(e) = self.close_variables(first_to_close + still_in_scope, Ok(None)) {
+                        break Err(e);
+                    }
+                    declared_at.truncate(still_in_scope);
+                    i = target + 1;
+                    continue;
+                }
+            }
+            break res;
+        };
+        res
+    }
+
+    // Calls `__close` on the `<close>` locals above `first`, most recent first, then gives back `res`.
+    // Each one is passed the error in flight, and an error raised by one of them replaces it
+    fn close_variables(&mut self, first: usize, mut res: Result<Option<Expr>, String>) -> Result<Option<Expr>, String> {
+        while self.to_be_closed.len() > first {
+            if let Some((value, close_method)) = self.to_be_closed.pop() {
+                let err = if let Err(msg) = &res { Value::String(msg.into()) } else { Value::Nil };
+                if let Err(e) = self.call_value(close_method, vec![value, err], Span::default()) {
+                    res = Err(e);
+                }
+            }
         }
-        Ok(None)
+        res
     }
 
-    fn complete_closure(&mut self, func: &mut Function) {
-        // for (name, value) in func.get_closure().table.as_ref().borrow_mut().iter_mut() {
-        //     if let Value::String(s) = name {
-        //         *value = self.find_var(&s).unwrap_or_else(|| {println!("Found var {s}, but it's nil"); Value::Nil}).clone();
-        //     } else {
-        //         panic!("Capturing variable that does not exist");
-        //     }
-        // }
-        func.set_closure(self.stack.clone())
+    // Gives a function being created the variables it captures, as listed by the resolver
+    fn complete_closure(&self, func: &mut Function) {
+        let frame = self.frame();
+        let upvalues = func.get_info().captures.iter().map(|capture| match capture.source {
+            VarKind::Local(slot) => frame.slots[slot].clone(),
+            VarKind::Upvalue(index) => frame.upvalues[index].clone(),
+            VarKind::Global => panic!("Internal error: global '{}' captured", capture.name),
+        }).collect();
+        func.set_closure(Closure { upvalues });
+    }
+
+    // Runs a resolved chunk, `main` is what `resolver::resolve` returned for it
+    pub fn exec_chunk(&mut self, chunk: &Stmt, main: &FunctionInfo) -> Result<(), String> {
+        self.frames.push(Frame::new(main.slots, vec![]));
+        let res = self.eval_stmt(chunk);
+        self.frames.pop();
+        res.map(|_| ())
     }
 
-    pub fn eval_stmt(&mut self, s: &Stmt) -> Result<Option<Expr>, String> {
-        match s {
-            Stmt::Empty => {
+    fn eval_stmt(&mut self, s: &Stmt) -> Result<Option<Expr>, String> {
+        match &s.kind {
+            StmtKind::Empty => {
                 return Ok(None);
             },
-            Stmt::ExprStmt(e) => {
-                self.eval_expr(&e);
+            StmtKind::ExprStmt(e) => {
+                self.eval_expr(&e)?;
                 Ok(None)
             },
-            Stmt::Assignment(var, val) => {
-                let mut val_vec = vec![];
-                if let Expr::Exprlist(el) = val {
-                    for e in el.into_iter() {
-                        let e_res = self.eval_expr(&e);
-                        if let Value::ValList(vl) = e_res {
-                            for v in vl.into_iter() {
-                                val_vec.push(v);
-                            }
-                        } else {
-                            if let Value::VarargsIdentifier = e_res {
-                               if let Some(Value::Varargs(varargs)) = self.get_current_stack_env().table.as_ref().borrow().get(&Value::VarargsIdentifier) {
-                                    for va in varargs {
-                                        val_vec.push(va.clone());
-                                    }
-                               }
-                            } else {
-                                val_vec.push(e_res);
-                            }
+            StmtKind::Assignment(var, val) => {
+                // Tables and keys of the targets are evaluated before the values, left to right
+                let mut targets = vec![];
+                if let ExprKind::Exprlist(var_list) = &var.kind {
+                    for var in var_list.iter() {
+                        match &var.kind {
+                            ExprKind::Var(var_name, kind) => targets.push(AssignTarget::Name(*kind, var_name.clone())),
+                            ExprKind::Accessor(table, key) => {
+                                let table = self.eval_expr(table.as_ref())?;
+                                let key = Self::normalize_key(self.eval_expr(key.as_ref())?);
+                                targets.push(AssignTarget::Field(table, key, var.span));
+                            },
+                            _ => return Err(format!("{}: cannot assign to this expression", var.span)),
                         }
                     }
                 }
-                if let Expr::Exprlist(var_list) = var {
-                    let mut val_counter = 0;
-                    for var in var_list.iter() {
-                        if let Expr::Var(var_name) = var {
-                            let t = self.stack.iter_mut().find(|entry| {entry.table.as_ref().borrow().get(&Value::String(var_name.to_string())) != None}).unwrap_or_else(|| &mut self._G);
-                            if let Some(val) = val_vec.get(val_counter) {
-                                t.table.as_ref().borrow_mut().insert(Value::String(var_name.clone()), val.clone());
-                            } else {
-                                t.table.as_ref().borrow_mut().insert(Value::String(var_name.clone()), Value::Nil);
+                let mut val_vec = vec![];
+                if let ExprKind::Exprlist(el) = &val.kind {
+                    val_vec = self.eval_expr_list(el)?;
+                }
+                for (i, target) in targets.into_iter().enumerate() {
+                    let value = val_vec.get(i).cloned().unwrap_or(Value::Nil);
+                    match target {
+                        AssignTarget::Name(kind, var_name) => self.set_var(kind, &var_name, value),
+                        AssignTarget::Field(table, key, span) => {
+                            if key == Value::Nil {
+                                return Err(format!("{}: index is nil", span));
+                            } else if matches!(key, Value::Number(n) if n.is_nan()) {
+                                return Err(format!("{}: index is NaN", span));
                             }
-                        } else if let Expr::Accessor(accessors, field) = var {
-                            let key = self.eval_expr(field.as_ref());
-                            let resolved_accessors = self.eval_expr(accessors.as_ref());
-                            if let Value::Table(accessed_table) = resolved_accessors {
-                                if let Some(GcValue::Table(accessed_table)) = self.gc.modify_value(&accessed_table) {
-                                    accessed_table.insert(key, val_vec[val_counter].clone());                                    
-                                }
+                            let Value::Table(accessed_table) = &table else {
+                                return Err(format!("{}: attempt to index a {} value", span, Self::type_name(&table)));
+                            };
+                            if let Some(GcValue::Table(accessed_table)) = self.gc.modify_value(accessed_table) {
+                                accessed_table.insert(key, value);
                             }
-                        }
-                        if let Some(Value::FunctionDef(fd)) = val_vec.get_mut(val_counter) {
-                            self.complete_closure(fd);
-                        }
-                        val_counter += 1;
+                        },
                     }
-                } else {
-                    return Err("Cannot assign to this".into());
                 }
                 return Ok(None);
             },
-            Stmt::LocalAssignment(var, val) => {
+            StmtKind::LocalAssignment(var, val, attribs) => {
                 let mut val_vec = vec![];
-                if let Expr::Exprlist(el) = val {
-                    for e in el.into_iter() {
-                        let val_res = self.eval_expr(&e);
-                        if let Value::VarargsIdentifier = val_res {
-                            if let Some(Value::Varargs(varargs)) = self.get_current_stack_env().table.as_ref().borrow().get(&Value::VarargsIdentifier) {
-                                for va in varargs {
-                                    val_vec.push(va.clone());
-                                }
-                            }
-                        } else {
-                            val_vec.push(val_res);                            
-                        }
-                    }
+                if let ExprKind::Exprlist(el) = &val.kind {
+                    val_vec = self.eval_expr_list(el)?;
                 }
-                if let Expr::Exprlist(var_list) = var {
+                if let ExprKind::Exprlist(var_list) = &var.kind {
                     let mut val_counter = 0;
                     for var in var_list.iter() {
-                        if let Expr::Var(var_name) = var {
-                            if let Some(val) = val_vec.get(val_counter) {
-                                self.get_current_stack_env().table.as_ref().borrow_mut().insert(Value::String(var_name.clone()), val.clone());
-                            } else {
-                                self.get_current_stack_env().table.as_ref().borrow_mut().insert(Value::String(var_name.clone()), Value::Nil);
+                        if let ExprKind::Var(var_name, _) = &var.kind {
+                            if let Some(Some(LocalAttrib::Close)) = attribs.get(val_counter) {
+                                let value = val_vec.get(val_counter).cloned().unwrap_or(Value::Nil);
+                                if let Some(close_method) = self.close_metamethod(&value, s.span, var_name)? {
+                                    self.to_be_closed.push((value, close_method));
+                                }
                             }
+                            self.declare(var, val_vec.get(val_counter).cloned().unwrap_or(Value::Nil));
                             val_counter += 1;
                         } else {
                             return Err("Cannot assign to this".into());
                         }
                     }
-                } else if &Expr::Literal(Value::VarargsIdentifier) == var {
-                    // varargs initialization case
-                    if let Expr::Literal(Value::Varargs(varargs)) = val {
-                        self.get_current_stack_env().table.as_ref().borrow_mut().insert(Value::VarargsIdentifier, Value::Varargs(varargs.clone()));                        
-                    }
                 } else {
                     return Err("Cannot assign to this".into());
                 }
                 return Ok(None);
 
             }
-            Stmt::Block(stmts) => {
+            StmtKind::Block(stmts) => {
                 return self.eval_block(stmts);
             },
-            Stmt::DoBlock(stmts) => {
-                self.push_env();
-                let eval_res = self.eval_block(stmts);
-                if let Ok(Some(expr)) = eval_res {
-                    let literal = self.eval_expr(&expr);
-                    self.pop_env();
-                    return Ok(Some(Expr::Literal(literal)));
-                } else if let Err(_) = eval_res {
-                    self.pop_env();
-                    return eval_res;
-                } else {
-                    self.pop_env();
-                    Ok(None)
-                }
+            StmtKind::DoBlock(stmts) => {
+                return self.eval_block(stmts);
             }
-            Stmt::IfStmt(cond, body, _else) => {
-                let cond_res = self.eval_expr(&cond);
+            StmtKind::IfStmt(cond, body, _else) => {
+                let cond_res = self.eval_expr(&cond)?;
                 let mut eval_res = Ok(None);
                 if self.is_truthy(&cond_res) {
-                    self.push_env();
                     eval_res = self.eval_stmt(&*body);
-                    self.pop_env();
                     if let Ok(None) = eval_res {
                         // do nothing
                     } else if let Ok(Some(ret)) = eval_res {
                         return Ok(Some(ret));
                     }
                 } else {
-                    self.push_env();
                     eval_res = self.eval_stmt(_else);
-                    self.pop_env();
                     if let Ok(None) = eval_res {
                         // do nothing
                     } else if let Ok(Some(ret)) = eval_res {
@@ -596,365 +696,619 @@ impl Interpreter {
                 }
                 eval_res
             },
-            Stmt::WhileLoop(cond, body) => {
+            StmtKind::WhileLoop(cond, body) => {
                 loop {
-                    let cond_res = self.eval_expr(&cond);
+                    let cond_res = self.eval_expr(&cond)?;
                     if self.is_truthy(&cond_res) {
-                        self.push_env();
                         let res = self.eval_stmt(&*body);
                         if let Err(s) = res {
-                            self.pop_env();
                             return Err(s);
                         } else if let Ok(None) = res {
                             // Do nothing
                         } else if let Ok(Some(ret)) = res {
-                            if let Expr::Literal(Value::Interrupt) = ret {
+                            if let ExprKind::Literal(Value::Interrupt) = ret.kind {
                                 break;
                             }
-                            self.pop_env();
                             return Ok(Some(ret));
                         }
-                        self.pop_env();
                     } else {
                         break;
                     }
                 }
                 Ok(None)
             },
-            Stmt::RepeatUntilLoop(body, cond) => {
+            StmtKind::RepeatUntilLoop(body, cond) => {
                 loop {
-                    self.push_env();
-                    let stmt_res = self.eval_stmt(&*body);
-                    if let Err(s) = stmt_res {
-                        return Err(s);
-                    } else if let Ok(None) = stmt_res {
-                        // Do nothing
-                    } else if let Ok(Some(Expr::Literal(Value::Interrupt))) = stmt_res {
-                        break;
-                    } else if let Ok(Some(ret)) = stmt_res {
-                        return Ok(Some(ret));
-                    }
-                    let cond_res = self.eval_expr(cond);
-                    if self.is_truthy(&cond_res) {
-                        self.pop_env();
-                        break;
+                    // The condition can see the locals of the body, so they are closed after it
+                    let first_to_close = self.to_be_closed.len();
+                    let stmt_res = match &body.kind {
+                        StmtKind::Block(stmts) => self.run_block(stmts),
+                        _ => self.eval_stmt(&*body),
+                    };
+                    let stmt_res = match stmt_res {
+                        Ok(None) => self.eval_expr(cond).map(|cond_res| {
+                            self.is_truthy(&cond_res).then(|| ExprKind::Literal(Value::Interrupt).into())
+                        }),
+                        stmt_res => stmt_res,
+                    };
+                    match self.close_variables(first_to_close, stmt_res)? {
+                        None => {},
+                        Some(Expr { kind: ExprKind::Literal(Value::Interrupt), .. }) => break,
+                        Some(ret) => return Ok(Some(ret)),
                     }
-                    self.pop_env();
                 }
                 Ok(None)
             },
-            Stmt::NumericForLoop(control_var, control_value, limit, step, body) => {
-                let step = self.eval_expr(step);
-                if let Value::Number(step_float) = step {
+            StmtKind::NumericForLoop(control_var, control_value, limit, step, body) => {
+                let control_value = self.eval_expr(control_value)?;
+                let limit = self.eval_expr(limit)?;
+                let step = self.eval_expr(step)?;
+                if let (Value::Integer(mut control_int), Value::Integer(step_int)) = (&control_value, &step) {
+                    if *step_int == 0 {
+                        return Err("In a numeric for loop, \"step\" cannot be 0".into());
+                    }
+                    // A float limit is clipped to the integers the loop can actually reach
+                    let limit_int = match limit {
+                        Value::Integer(i) => i,
+                        Value::Number(f) if *step_int > 0 => if f.floor() >= i64::MAX as f64 { i64::MAX } else { f.floor() as i64 },
+                        Value::Number(f) => if f.ceil() <= i64::MIN as f64 { i64::MIN } else { f.ceil() as i64 },
+                        _ => return Err("\"limit\" is required to be a number".into()),
+                    };
+                    while (*step_int > 0 && control_int <= limit_int) ||
+                    (*step_int < 0 && control_int >= limit_int) {
+                        if let Some(exit) = self.for_iteration(control_var, vec![Value::Integer(control_int)], body)? {
+                            if let ExprKind::Literal(Value::Interrupt) = exit.kind {
+                                break;
+                            }
+                            return Ok(Some(exit));
+                        }
+                        match control_int.checked_add(*step_int) {
+                            Some(next) => control_int = next,
+                            None => break
+                        }
+                    }
+                    return Ok(None);
+                }
+                if let Some(step_float) = Self::to_float(&step) {
                     if step_float == OrderedFloat(0.0) {
-                        panic!("In a numeric for loop, \"step\" cannot be 0");
+                        return Err("In a numeric for loop, \"step\" cannot be 0".into());
                     }
-                    let control_value = self.eval_expr(control_value);
-                    if let Value::Number(mut control_float) = control_value {
-                        let limit = self.eval_expr(limit);
-                        if let Value::Number(limit_float) = limit {
+                    if let Some(mut control_float) = Self::to_float(&control_value) {
+                        if let Some(limit_float) = Self::to_float(&limit) {
                             while (step_float > OrderedFloat(0.0) && control_float <= limit_float) ||
                             (step_float < OrderedFloat(0.0) && control_float >= limit_float) {
-                                self.push_env();
-                                let control_stmt = Stmt::LocalAssignment(control_var.clone(), Expr::Exprlist(vec![Expr::Literal(Value::Number(control_float))]));
-                                self.eval_stmt(&control_stmt)?;
-                                for s in body {
-                                    self.eval_stmt(s)?;
+                                if let Some(exit) = self.for_iteration(control_var, vec![Value::Number(control_float)], body)? {
+                                    if let ExprKind::Literal(Value::Interrupt) = exit.kind {
+                                        break;
+                                    }
+                                    return Ok(Some(exit));
                                 }
-                                self.pop_env();
                                 control_float += step_float;
                             }
                             return Ok(None);
                         } else {
-                            panic!("\"limit\" is required to be a number");
+                            return Err("\"limit\" is required to be a number".into());
                         }
                     } else {
-                        panic!("\"control\" is required to be a number")
+                        return Err("\"control\" is required to be a number".into());
                     }
                 } else {
-                    panic!("\"step\" is required to be a number")
+                    return Err("\"step\" is required to be a number".into());
                 }
             }
-            Stmt::Return(ret) => {
-                Ok(Some(ret.clone()))
+            StmtKind::GenericForLoop(names, exprs, body) => {
+                let mut values = vec![];
+                if let ExprKind::Exprlist(el) = &exprs.kind {
+                    values = self.eval_expr_list(el)?;
+                }
+                let mut values = values.into_iter();
+                let iterator = values.next().unwrap_or(Value::Nil);
+                let state = values.next().unwrap_or(Value::Nil);
+                let control = values.next().unwrap_or(Value::Nil);
+                let closing = values.next().unwrap_or(Value::Nil);
+                let close_method = self.close_metamethod(&closing, s.span, "(for state)")?;
+                let res = self.generic_for_iterations(names, iterator, state, control, body, s.span);
+                if let Some(close_method) = close_method {
+                    let err = if let Err(msg) = &res { Value::String(msg.into()) } else { Value::Nil };
+                    self.call_value(close_method, vec![closing, err], s.span)?;
+                }
+                res
             },
-            Stmt::Break => Ok(Some(Expr::Literal(Value::Interrupt))),
-            Stmt::Chunk(stmts) => {
-                for s in stmts.iter() {
-                    let res = self.eval_stmt(s);
-                    if !res.is_ok() {
-                        return res;
-                    } else if let Ok(Some(e)) = res {
-                        if let Expr::Literal(Value::Interrupt) = e {
-                            return Err("Break outside loop".into());
-                        }
-                    }
+            StmtKind::Return(ret) => {
+                // Evaluated here, while the locals of the enclosing blocks are still in scope
+                let ret_val = self.eval_expr(ret)?;
+                Ok(Some(ExprKind::Literal(ret_val).into()))
+            },
+            StmtKind::LocalFunction(name, func) => {
+                // The local exists before the function is created, so the body can refer to itself
+                self.declare(name, Value::Nil);
+                let func_val = self.eval_expr(func)?;
+                if let ExprKind::Var(name, kind) = &name.kind {
+                    self.set_var(*kind, name, func_val);
                 }
                 Ok(None)
+            },
+            StmtKind::Break => Ok(Some(ExprKind::Literal(Value::Interrupt).into())),
+            StmtKind::Goto(label) => Ok(Some(ExprKind::Literal(Value::Goto(label.clone())).into())),
+            StmtKind::Label(_) => Ok(None),
+            StmtKind::Chunk(stmts) => {
+                let res = self.eval_block(stmts);
+                if let Ok(Some(Expr { kind: ExprKind::Literal(Value::Interrupt), .. })) = res {
+                    return Err("Break outside loop".into());
+                }
+                res.map(|_| None)
             }
         }
     }
     
     
-    fn eval_expr(&mut self, expr: &Expr) -> Value {
-        match expr {
-            Expr::Binary(o1, op, o2) => {
+    fn eval_expr(&mut self, expr: &Expr) -> Result<Value, String> {
+        match &expr.kind {
+            ExprKind::Binary(o1, op, o2) => {
                 match op {
                     Token::Plus => {
-                        let t1 = self.eval_expr(&*o1);
-                        let t2 = self.eval_expr(&*o2);
+                        let t1 = self.eval_expr(&*o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
                         return self.add_vals(&t1, &t2);
                     },
                     Token::Minus => {
-                        let t1 = self.eval_expr(&*o1);
-                        let t2 = self.eval_expr(&*o2);
+                        let t1 = self.eval_expr(&*o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
                         return self.subtract_vals(t1, t2);
                     },
                     Token::Star => {
-                        let t1 = self.eval_expr(&*o1);
-                        let t3 = self.eval_expr(&*o2);
+                        let t1 = self.eval_expr(&*o1)?;
+                        let t3 = self.eval_expr(&*o2)?;
                         return self.multiply_vals(t1, t3);
                     },
                     Token::ForwardSlash => {
-                        let t1 = self.eval_expr(&*o1);
-                        let t2 = self.eval_expr(&*o2);
+                        let t1 = self.eval_expr(&*o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
                         return self.divide_vals(t1, t2);
                     },
                     Token::LessThanOrEqual => {
-                        let t1 = self.eval_expr(&*o1);
-                        let t2 = self.eval_expr(&*o2);
+                        let t1 = self.eval_expr(&*o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
                         return self.less_than_or_equal(t1, t2);
                     },
                     Token::LessThan => {
-                        let t1 = self.eval_expr(&*o1);
-                        let t2 = self.eval_expr(&*o2);
+                        let t1 = self.eval_expr(&*o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
                         return self.less_than(t1, t2);
                     },
                     Token::Equals => {
-                        let t1 = self.eval_expr(*&o1);
-                        let t2 = self.eval_expr(&*o2);
-                        return self.equals(t1, t2);
+                        let t1 = self.eval_expr(*&o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
+                        return Ok(self.equals(t1, t2));
                     },
                     Token::NotEquals => {
-                        let t1 = self.eval_expr(*&o1);
-                        let t2 = self.eval_expr(&*o2);
+                        let t1 = self.eval_expr(*&o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
                         let to_negate = self.equals(t1, t2);
                         if let Value::Boolean(b) = to_negate {
-                            return Value::Boolean(!b);
+                            return Ok(Value::Boolean(!b));
                         } else {
                             panic!("Internal error: equality should always return boolean");
                         }
                     }
                     Token::GreaterThanOrEqual => {
-                        let t1 = self.eval_expr(&*o1);
-                        let t2 = self.eval_expr(&*o2);
+                        let t1 = self.eval_expr(&*o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
                         return self.greater_than_or_equal(t1, t2);
                     },
                     Token::GreaterThan => {
-                        let t1 = self.eval_expr(&*o1);
-                        let t2 = self.eval_expr(&*o2);
+                        let t1 = self.eval_expr(&*o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
                         return self.greater_than(t1, t2);
                     },
                     Token::Concatenation => {
-                        let t1 = self.eval_expr(&*o1);
-                        let t2 = self.eval_expr(&*o2);
+                        let t1 = self.eval_expr(&*o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
                         let s1 = self.stringify(t1);
                         let s2 = self.stringify(t2);
                         if let Ok(Value::String(s1)) = s1 {
                             if let Ok(Value::String(s2)) = s2 {
-                                return Value::String(s1 + &s2);
+                                return Ok(Value::String(s1.concat(&s2)));
                             }
                         }
-                        panic!("Cannot concatenate");
+                        return Err("Cannot concatenate".into());
                     },
                     Token::And => {
-                        let v1 = self.eval_expr(&*o1);
+                        let v1 = self.eval_expr(&*o1)?;
                         if !self.is_truthy(&v1) {
-                            return v1;
+                            return Ok(v1);
                         }
                         return self.eval_expr(&*o2);
                     },
                     Token::Or => {
-                        let v1 = self.eval_expr(&*o1);
+                        let v1 = self.eval_expr(&*o1)?;
                         if self.is_truthy(&v1) {
-                            return v1;
+                            return Ok(v1);
                         }
                         return self.eval_expr(&*o2);
                     },
                     Token::Percent => {
-                        let t1 = self.eval_expr(&*o1);
-                        let t2 = self.eval_expr(&*o2);
+                        let t1 = self.eval_expr(&*o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
                         return self.modulo_vals(t1, t2);
+                    },
+                    Token::Caret => {
+                        let t1 = self.eval_expr(&*o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
+                        return self.pow_vals(t1, t2);
+                    },
+                    Token::DoubleForwardSlash => {
+                        let t1 = self.eval_expr(&*o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
+                        return self.floor_divide_vals(t1, t2);
+                    },
+                    Token::Ampersand | Token::Pipe | Token::Tilde | Token::ShiftLeft | Token::ShiftRight => {
+                        let t1 = self.eval_expr(&*o1)?;
+                        let t2 = self.eval_expr(&*o2)?;
+                        return self.bitwise_vals(t1, op, t2);
                     }
                     _ => panic!("Operator not supported yet")
                 }
             },
-            Expr::Literal(t) => {
-                if let Value::FunctionDef(fd) = t.clone().borrow_mut() {
-                    self.complete_closure(fd)
+            ExprKind::Literal(t) => {
+                if let Value::FunctionDef(fd) = t {
+                    let mut fd = fd.clone();
+                    self.complete_closure(&mut fd);
+                    return Ok(Value::FunctionDef(fd));
                 }
-                t.clone()
+                Ok(t.clone())
             },
-            Expr::Unary(e, op) => {
+            ExprKind::Unary(e, op) => {
                 if op == &Token::Minus {
-                    if let Expr::Literal(t) = &**e {
-                        if let Value::Number(n) = t {
-                            return Value::Number(-n);
-                        } else {
-                            panic!("Unsupported negation");
-                        }
-                    } else if let Expr::Grouping(expr) = &**e {
-                    let eval_res = self.eval_expr(&*expr);
-                        if let Value::Number(i) = eval_res {
-                            return Value::Number(-i);
-                        } else {
-                            panic!("Grouping token should return number literals");
-                        }
-                    } else {
-                        panic!("Cannot negate this");
+                    let operand = self.eval_expr(e)?;
+                    if let Value::Number(n) = operand {
+                        return Ok(Value::Number(-n));
+                    } else if let Value::Integer(i) = operand {
+                        return Ok(Value::Integer(i.wrapping_neg()));
                     }
+                    return self.binary_metamethod(&operand, &operand, "__unm");
                 } else if op == &Token::Not {
-                    let to_not = &self.eval_expr(e);
-                    return Value::Boolean(!self.is_truthy(to_not)); 
+                    let to_not = &self.eval_expr(e)?;
+                    return Ok(Value::Boolean(!self.is_truthy(to_not))); 
                 } else if op == &Token::Pound {
-                    let to_measure = self.eval_expr(e);
-                    return self.value_length(&to_measure).unwrap_or_else(|| Value::Nil);
+                    let to_measure = self.eval_expr(e)?;
+                    return Ok(self.value_length(&to_measure).unwrap_or_else(|| Value::Nil));
+                } else if op == &Token::Tilde {
+                    let operand = self.eval_expr(e)?;
+                    if let Some(i) = arith::to_integer(&operand) {
+                        return Ok(Value::Integer(!i));
+                    } else if Self::to_float(&operand).is_some() {
+                        return Err("number has no integer representation".into());
+                    }
+                    return self.binary_metamethod(&operand, &operand, "__bnot");
                 } else {
                     panic!("Unsupported unary operation");
                 }
             },
-            Expr::Grouping(e) => {
-                if let Expr::Exprlist(el) = &**e {
+            ExprKind::Grouping(e) => {
+                if let ExprKind::Exprlist(el) = &e.kind {
                     if el.len() == 1 {
                         return self.eval_expr(&el[0]);
                     }
                 }
                 return self.eval_expr(&*e);
             },
-            Expr::Var(s) => {
-                if let Some(v) = self.find_var(s) {
-                    return v.clone();
-                }
-                Value::Nil
-            },
-            Expr::Exprlist(el) => {
-                if el.len() == 1 {
-                    if let Some(e) = el.get(0) {
-                        return self.eval_expr(e);
-                    }
-                }
-                let mut values: Vec<Value> = vec![];
-                for e in el.iter() {
-                    values.push(self.eval_expr(e));
+            ExprKind::Var(s, kind) => Ok(self.get_var(*kind, s)),
+            ExprKind::Exprlist(el) => {
+                let mut values = self.eval_expr_list(el)?;
+                if values.len() == 1 {
+                    return Ok(values.remove(0));
                 }
-                return Value::ValList(values);
+                return Ok(Value::ValList(values));
             },
-            Expr::FunctionCall(func_id, vars) => {
-                let func_val = self.eval_expr(&**func_id);
-                    match func_val {
-                        Value::FunctionDef(fd) => {
-                            return self.call_fn(&fd, vars);
-                        },
-                        Value::NativeFunctionDef(nf) => {
-                            let mut args: Vec<Value> = vec![];
-                            for p in vars.iter() {
-                                args.push(self.eval_expr(p));
-                            }
-                            self.push_env();
-                            let func_eval = nf.call(self, &mut args);
-                            self.pop_env();
-                            if let Some(ret_val) = func_eval {
-                                return ret_val;
-                            }
-                        },
-                        Value::Nil => {
-                            println!("Cannot call nil");
-                        },
-                        _ => {
-                            println!("Cannot call value");
-                        }
-                    }
-                
-                return Value::Nil;
+            ExprKind::FunctionCall(..) | ExprKind::MethodCall(..) | ExprKind::Varargs => {
+                // Used as a single value, only the first result is kept
+                return Ok(self.eval_multi(expr)?.into_iter().next().unwrap_or(Value::Nil));
             },
-            Expr::Accessor(bt, ba) => {
-                if let Value::Table(ut) = self.eval_expr(bt.as_ref()) {
-                    let accessor = self.eval_expr(ba.as_ref());
+            ExprKind::Accessor(bt, ba) => {
+                if let Value::Table(ut) = self.eval_expr(bt.as_ref())? {
+                    let accessor = Self::normalize_key(self.eval_expr(ba.as_ref())?);
                     let table = self.get_table(&ut).unwrap();
                     if let Some(accessed_value) = table.get(&accessor) {
-                        return accessed_value.clone();
+                        return Ok(accessed_value.clone());
                     }
-                } else if let Expr::Accessor(_, _) = bt.as_ref() {
+                } else if let ExprKind::Accessor(_, _) = &bt.kind {
                     return self.eval_expr(bt.as_ref());
                 }
-                Value::Nil
+                Ok(Value::Nil)
             },
-            Expr::FieldList(fl) => {
+            ExprKind::FieldList(fl) => {
                 let mut user_table = crate::table::Table::new();
-                for (key, value) in fl.into_iter() {
-                    user_table.insert(self.eval_expr(&*key), self.eval_expr(&*value));
+                let mut position: i64 = 1;
+                // Nil values leave no entry behind, but still take up their position
+                let mut insert = |key: Value, value: Value| if value != Value::Nil {
+                    user_table.insert(key, value);
+                };
+                for (i, field) in fl.iter().enumerate() {
+                    match field {
+                        // Only the last field of the constructor can spread into several entries
+                        Field::Positional(value) if i + 1 == fl.len() => {
+                            for v in self.eval_multi(value)? {
+                                insert(Value::Integer(position), v);
+                                position = position.wrapping_add(1);
+                            }
+                        },
+                        Field::Positional(value) => {
+                            insert(Value::Integer(position), self.eval_expr(value)?);
+                            position = position.wrapping_add(1);
+                        },
+                        Field::Keyed(key, value) => {
+                            let key = Self::normalize_key(self.eval_expr(key)?);
+                            insert(key, self.eval_expr(value)?);
+                        },
+                    }
                 }
                 let gc_key = GcKey::new();
                 self.gc.store(gc_key.clone(), GcValue::Table(user_table));
-                return Value::Table(gc_key);
+                return Ok(Value::Table(gc_key));
             },
-            Expr::Varargs => {
-                return Value::VarargsIdentifier
+        }
+    }
+
+    // Runs one iteration of a for loop, with fresh loop variables bound to `values`
+    fn for_iteration(&mut self, vars: &Expr, values: Vec<Value>, body: &[Stmt]) -> Result<Option<Expr>, String> {
+        let mut values = values.into_iter();
+        if let ExprKind::Exprlist(vars) = &vars.kind {
+            for var in vars.iter() {
+                self.declare(var, values.next().unwrap_or(Value::Nil));
             }
         }
+        self.eval_block(body)
     }
 
-    fn call_fn(&mut self, fd: &Function, vars: &Vec<Expr>) -> Value {
-        let mut arg_values: Vec<Value> = vec![];
-        for v in vars {
-            let arg_value = self.eval_expr(v);
-            if let Value::ValList(mut vl) = arg_value {
-                arg_values.append(&mut vl);
-            } else {
-                arg_values.push(arg_value);
+    // Calls the iterator with the state and the control value until its first result is nil
+    fn generic_for_iterations(&mut self, names: &Expr, iterator: Value, state: Value, mut control: Value, body: &[Stmt], span: Span) -> Result<Option<Expr>, String> {
+        if !matches!(iterator, Value::FunctionDef(_) | Value::NativeFunctionDef(_)) {
+            return Err(format!("{}: attempt to call a {} value (for iterator)", span, if iterator == Value::Nil { "nil" } else { "non-function" }));
+        }
+        loop {
+            let results = match self.call_value(iterator.clone(), vec![state.clone(), control.clone()], span)? {
+                Value::ValList(vl) => vl,
+                v => vec![v],
+            };
+            control = results.first().cloned().unwrap_or(Value::Nil);
+            if control == Value::Nil {
+                return Ok(None);
+            }
+            if let Some(exit) = self.for_iteration(names, results, body)? {
+                if let ExprKind::Literal(Value::Interrupt) = exit.kind {
+                    return Ok(None);
+                }
+                return Ok(Some(exit));
             }
         }
-        let mut args_decls: Vec<Stmt> = vec![];
-        let mut arg_counter = 0;
-        for param in fd.get_params() {
-            if param == &Expr::Varargs {
-                let mut vararg_values: Vec<Value> = vec![];
-                for a in &arg_values[arg_counter..] {
-                    vararg_values.push(a.clone());
+    }
+
+    // The `__close` metamethod of a to-be-closed value. nil and false need no closing
+    fn close_metamethod(&self, v: &Value, span: Span, var_name: &str) -> Result<Option<Value>, String> {
+        if let Value::Nil | Value::Boolean(false) = v {
+            return Ok(None);
+        }
+        if let Value::Table(t) = v {
+            if let Some(meta_key) = self.get_table(t).and_then(Self::get_metatable) {
+                if let Some(close) = self.get_table(&meta_key).and_then(|m| m.get(&Value::String("__close".into()))) {
+                    return Ok(Some(close.clone()));
                 }
-                args_decls.push(Stmt::LocalAssignment(Expr::Literal(Value::VarargsIdentifier), Expr::Literal(Value::Varargs(vararg_values))));
-                break;
             }
-            args_decls.push(Stmt::LocalAssignment(Expr::Exprlist(vec![param.clone()]), Expr::Exprlist(vec![Expr::Literal(arg_values.get(arg_counter).unwrap_or_else(|| &Value::Nil).clone())])));
-            arg_counter += 1;
         }
-        let func_body = fd.get_body();
-        for c in fd.get_closure().clone() {
-            self.push_custom_env(c.clone());
+        Err(format!("{}: variable '{}' got a non-closable value", span, var_name))
+    }
+
+    // The entry following `key` in a traversal of the table, skipping the metatable slot and nil values
+    fn table_next(&self, t: &GcKey, key: &Value) -> Result<Option<(Value, Value)>, String> {
+        let Some(table) = self.get_table(t) else {
+            return Ok(None);
+        };
+        let mut entries = table.iter().filter(|(k, v)| **k != Value::MetaKey && **v != Value::Nil);
+        if *key != Value::Nil {
+            let key = Self::normalize_key(key.clone());
+            if entries.by_ref().find(|(k, _)| **k == key).is_none() {
+                return Err("invalid key to 'next'".into());
+            }
+        }
+        Ok(entries.next().map(|(k, v)| (k.clone(), v.clone())))
+    }
+
+    // All the values of an expression. Calls and `...` can give any number of them, everything else exactly one
+    fn eval_multi(&mut self, expr: &Expr) -> Result<Vec<Value>, String> {
+        let results = match &expr.kind {
+            ExprKind::FunctionCall(func_id, vars) => {
+                let func_val = self.eval_expr(&**func_id)?;
+                let args = self.eval_expr_list(vars)?;
+                self.call_value(func_val, args, expr.span)?
+            },
+            ExprKind::MethodCall(receiver, name, vars) => {
+                // The receiver is evaluated once and reused as the implicit `self` argument
+                let receiver = self.eval_expr(&**receiver)?;
+                let Value::Table(ut) = &receiver else {
+                    println!("{}: Cannot index a non-table value to call method '{name}'", expr.span);
+                    return Ok(vec![Value::Nil]);
+                };
+                let method = self.get_table(ut).and_then(|t| t.get(&Value::String(name.into()))).cloned().unwrap_or(Value::Nil);
+                let mut args = vec![receiver];
+                args.append(&mut self.eval_expr_list(vars)?);
+                self.call_value(method, args, expr.span)?
+            },
+            ExprKind::Varargs => return Ok(self.varargs()),
+            _ => return Ok(vec![self.eval_expr(expr)?]),
+        };
+        match results {
+            Value::ValList(vl) => Ok(vl),
+            v => Ok(vec![v]),
+        }
+    }
+
+    // Evaluates an expression list left to right. Every expression but the last is cut to one value,
+    // the last one gives all of its values
+    fn eval_expr_list(&mut self, exprs: &[Expr]) -> Result<Vec<Value>, String> {
+        let mut values: Vec<Value> = vec![];
+        if let Some((last, init)) = exprs.split_last() {
+            for e in init {
+                values.push(self.eval_expr(e)?);
+            }
+            values.append(&mut self.eval_multi(last)?);
+        }
+        Ok(values)
+    }
+
+    // The extra arguments of the running function, the resolver makes sure it is a vararg one
+    fn varargs(&self) -> Vec<Value> {
+        self.frame().varargs.clone()
+    }
+
+    fn call_value(&mut self, func_val: Value, mut args: Vec<Value>, span: Span) -> Result<Value, String> {
+        match func_val {
+            Value::FunctionDef(fd) => {
+                return self.call_fn(&fd, args);
+            },
+            Value::NativeFunctionDef(nf) => {
+                let func_eval = nf.call(self, &mut args)?;
+                // A native function that returns nothing gives no values
+                return Ok(func_eval.unwrap_or(Value::ValList(vec![])));
+            },
+            Value::Nil => {
+                println!("{}: Cannot call nil", span);
+            },
+            _ => {
+                println!("{}: Cannot call value", span);
+            }
         }
-        for decl in args_decls.into_iter() {
-            if let Err(e) = self.eval_stmt(&decl) {
-                panic!("Error declaring args: {e}");
+        return Ok(Value::Nil);
+    }
+
+    fn call_fn(&mut self, fd: &Function, arg_values: Vec<Value>) -> Result<Value, String> {
+        // The body sees the variables captured where the function was created, not the caller's
+        self.frames.push(Frame::new(fd.get_info().slots, fd.get_closure().upvalues.clone()));
+        let mut args = arg_values.into_iter();
+        for param in fd.get_params() {
+            if param.kind == ExprKind::Varargs {
+                if let Some(frame) = self.frames.last_mut() {
+                    frame.varargs = args.by_ref().collect();
+                }
+                break;
             }
+            self.declare(param, args.next().unwrap_or(Value::Nil));
         }
-        let func_eval = self.eval_stmt(&func_body);
-        if let Err(func_body_err) = func_eval {
-            println!("{func_body_err}");
-        } else if let Ok(None) = func_eval {
-            // Do nothing
-        } else if let Ok(Some(func_ret)) = func_eval {
-            if let Expr::Literal(Value::Interrupt) = func_ret {
-                panic!("Break outside loop");
+        let func_eval = self.eval_stmt(fd.get_body());
+        self.frames.pop();
+        if let Some(func_ret) = func_eval? {
+            // Return statements hand back their values as a literal
+            match func_ret.kind {
+                ExprKind::Literal(Value::Interrupt) => return Err("Break outside loop".into()),
+                ExprKind::Literal(ret_val) => return Ok(ret_val),
+                _ => panic!("Internal error: function returned an unevaluated expression"),
             }
-            let ret_val = self.eval_expr(&func_ret);
-            self.pop_env();
-            return ret_val;
         }
-        self.pop_env();
-        return Value::Nil;
+        // Falling off the end of a function returns no values
+        return Ok(Value::ValList(vec![]));
+    }
+
+    pub fn optimize_enabled(&self) -> bool {
+        self.optimize
+    }
+
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser, resolver, visitor::{Folder, walk_fold_expr}};
+
+    // Forgets where expressions came from, like a pass that builds its nodes with `Expr::from`
+    struct ClearSpans;
+
+    impl Folder for ClearSpans {
+        fn fold_expr(&mut self, expr: Expr) -> Expr {
+            walk_fold_expr(self, expr).kind.into()
+        }
+    }
+
+    // What running the main chunk of `src` gives: the value it returns, or an error
+    fn eval(src: &str) -> Result<Option<Expr>, String> {
+        let tokens = Lexer::new(src).tokenize().expect("source should lex");
+        let mut chunk = Parser::new(tokens).chunk().ok().expect("source should parse");
+        let main = resolver::resolve(&mut chunk).ok().expect("source should resolve");
+        let StmtKind::Chunk(stmts) = ClearSpans.fold_stmt(chunk).kind else { panic!("not a chunk") };
+        let mut interp = Interpreter::new();
+        interp.frames.push(Frame::new(main.slots, vec![]));
+        interp.eval_block(&stmts)
+    }
+
+    // The value returned by the main chunk of `src`
+    fn run(src: &str) -> Value {
+        match eval(src) {
+            Ok(Some(Expr { kind: ExprKind::Literal(v), .. })) => v,
+            _ => panic!("chunk should return a value"),
+        }
+    }
+
+    #[test]
+    fn only_positional_fields_expand() {
+        let f = "local function f() return 1, 2 end\n";
+        assert!(run(&format!("{f}local t = {{f()}}\nreturn t[2]")) == Value::Integer(2));
+        assert!(run(&format!("{f}local t = {{[1] = f()}}\nreturn t[2]")) == Value::Nil);
+        assert!(run(&format!("{f}local t = {{x = f()}}\nreturn t[2]")) == Value::Nil);
+        assert!(run(&format!("{f}local t = {{f(), 5}}\nreturn t[2]")) == Value::Integer(5));
+    }
+
+    #[test]
+    fn nil_fields_leave_no_entry() {
+        assert!(run("local function f(...) return #{...} end\nreturn f(1, nil)") == Value::Integer(1));
+        assert!(run("local t = {x = nil, 1}\nreturn #t") == Value::Integer(1));
+        assert!(run("local t = {nil, 2}\nreturn t[2]") == Value::Integer(2));
+    }
+
+    #[test]
+    fn rejects_nil_and_nan_keys() {
+        assert!(matches!(eval("local t = {}\nt[nil] = 1"), Err(e) if e.ends_with("index is nil")));
+        assert!(matches!(eval("local t = {}\nt[0/0] = 1"), Err(e) if e.ends_with("index is NaN")));
+        assert!(run("local t = {}\nt[0.5] = 1\nreturn t[0.5]") == Value::Integer(1));
+    }
+
+    #[test]
+    fn errors_close_variables() {
+        let closing = "local x <close> = setmetatable({}, {__close = function(_, e) assert(false, \"closed after \" .. e) end})\n";
+        assert!(matches!(eval(&format!("do {closing}assert(false, \"boom\") end")), Err(e) if e == "closed after boom"));
+        let f = "local function f(n) if n == 0 then assert(false, \"boom\") end return f(n - 1) end\n";
+        assert!(matches!(eval(&format!("{f}do {closing}f(2) end")), Err(e) if e == "closed after boom"));
+    }
+
+    #[test]
+    fn repeat_closes_after_condition() {
+        let src = "local closed, seen = false, nil\n\
+            local function check() seen = closed return true end\n\
+            repeat local x <close> = setmetatable({}, {__close = function() closed = true end}) until x and check()\n\
+            return closed and not seen";
+        assert!(run(src) == Value::Boolean(true));
+    }
+
+    #[test]
+    fn collector_keeps_what_closures_capture() {
+        let mk = "local function mk() local t = {5} return function() return t[1] end end\n";
+        assert!(run(&format!("{mk}local g = mk()\ncollectgarbage()\nreturn g()")) == Value::Integer(5));
+        assert!(run(&format!("{mk}local t = {{inner = {{f = mk()}}}}\ncollectgarbage()\nreturn t.inner.f()")) == Value::Integer(5));
+        let f = "local function f(n) if n == 0 then return {7} end return f(n - 1) end\n";
+        assert!(run(&format!("{f}collectgarbage()\nreturn f(2)[1]")) == Value::Integer(7));
     }
 
-    pub fn get_stack(&self) -> &VecDeque<UserTable> {
-        &self.stack
+    // Like the REPL, every line is a chunk of its own running on the same interpreter
+    #[test]
+    fn chunks_share_globals() {
+        let mut interp = Interpreter::new();
+        for line in ["function f(a) local x, y = 1, 2 return a + x + y end", "r = f(1)"] {
+            let tokens = Lexer::new(line).tokenize().expect("source should lex");
+            let mut chunk = Parser::new(tokens).chunk().ok().expect("source should parse");
+            let main = resolver::resolve(&mut chunk).ok().expect("source should resolve");
+            interp.exec_chunk(&chunk, &main).expect("chunk should run");
+        }
+        assert!(interp.get_var(VarKind::Global, "r") == Value::Integer(4));
     }
 }
@@ -0,0 +1,101 @@
+use crate::{Stmt, expr::ExprKind, stmt::StmtKind, parser::ParseError};
+
+// A label that a goto can reach. `blocked_by` names the first local a forward jump to it would skip over,
+// `earlier` tells whether it was defined before the statement being checked
+#[derive(Clone)]
+struct VisibleLabel {
+    name: String,
+    line: usize,
+    blocked_by: Option<String>,
+    earlier: bool,
+}
+
+// Checks every goto in a function body (or the main chunk) against Lua's rules: the label has to be visible,
+// may only be defined once among the visible labels, and the jump may not enter the scope of a local.
+// Nested functions are checked on their own when they are parsed, labels are not visible across them.
+pub fn check_labels(body: &[Stmt]) -> Result<(), ParseError> {
+    check_block(body, &[], true)
+}
+
+fn local_name(stmt: &Stmt) -> Option<String> {
+    match &stmt.kind {
+        StmtKind::LocalAssignment(vars, _, _) => {
+            if let ExprKind::Exprlist(vars) = &vars.kind {
+                if let Some(ExprKind::Var(name, _)) = vars.first().map(|v| &v.kind) {
+                    return Some(name.clone());
+                }
+            }
+            None
+        },
+        StmtKind::LocalFunction(name, _) => match &name.kind {
+            ExprKind::Var(name, _) => Some(name.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// `end_label_ok` is false for repeat-until bodies, whose locals are still in scope in the condition
+fn check_block(stmts: &[Stmt], enclosing: &[VisibleLabel], end_label_ok: bool) -> Result<(), ParseError> {
+    let mut own_labels: Vec<(usize, &String)> = vec![];
+    for (i, s) in stmts.iter().enumerate() {
+        if let StmtKind::Label(name) = &s.kind {
+            let previous = own_labels.iter().map(|(j, n)| (*n, stmts[*j].span.line))
+                .chain(enclosing.iter().filter(|l| l.earlier).map(|l| (&l.name, l.line)))
+                .find(|(n, _)| *n == name);
+            if let Some((_, line)) = previous {
+                return Err(ParseError::semantic(s.span, format!("label '{}' already defined on line {}", name, line)));
+            }
+            own_labels.push((i, name));
+        }
+    }
+    // A label followed only by void statements is at the end of the block, where no local is in scope any more
+    let is_at_end = |j: usize| end_label_ok && stmts[j + 1..].iter().all(|s| matches!(s.kind, StmtKind::Label(_) | StmtKind::Empty));
+    let visible_from = |i: usize| -> Vec<VisibleLabel> {
+        let mut visible: Vec<VisibleLabel> = enclosing.to_vec();
+        for (j, name) in own_labels.iter() {
+            let blocked_by = if *j > i && !is_at_end(*j) {
+                stmts[i + 1..*j].iter().find_map(local_name)
+            } else {
+                None
+            };
+            visible.push(VisibleLabel { name: (*name).clone(), line: stmts[*j].span.line, blocked_by, earlier: *j < i });
+        }
+        visible
+    };
+
+    for (i, s) in stmts.iter().enumerate() {
+        match &s.kind {
+            StmtKind::Goto(name) => {
+                match visible_from(i).into_iter().rev().find(|l| l.name == *name) {
+                    Some(VisibleLabel { blocked_by: Some(local), .. }) => {
+                        return Err(ParseError::semantic(s.span, format!("<goto {}> at line {} jumps into the scope of local '{}'", name, s.span.line, local)));
+                    },
+                    Some(_) => {},
+                    None => return Err(ParseError::semantic(s.span, format!("no visible label '{}' for <goto> at line {}", name, s.span.line))),
+                }
+            },
+            _ => check_nested(s, &visible_from(i))?,
+        }
+    }
+    Ok(())
+}
+
+fn check_nested(s: &Stmt, visible: &[VisibleLabel]) -> Result<(), ParseError> {
+    match &s.kind {
+        StmtKind::Block(stmts) | StmtKind::DoBlock(stmts) => check_block(stmts, visible, true),
+        StmtKind::NumericForLoop(_, _, _, _, body) | StmtKind::GenericForLoop(_, _, body) => check_block(body, visible, true),
+        StmtKind::WhileLoop(_, body) => check_nested(body, visible),
+        StmtKind::RepeatUntilLoop(body, _) => {
+            if let StmtKind::Block(stmts) = &body.kind {
+                return check_block(stmts, visible, false);
+            }
+            check_nested(body, visible)
+        },
+        StmtKind::IfStmt(_, body, else_body) => {
+            check_nested(body, visible)?;
+            check_nested(else_body, visible)
+        },
+        _ => Ok(()),
+    }
+}
@@ -1,11 +1,75 @@
 use crate::Token;
 use crate::Value;
+use ordered_float::OrderedFloat;
+use crate::span::Span;
+use crate::tokens::{SpannedToken, Trivia, TriviaKind, LosslessToken, LosslessTokens};
+use std::fmt::Display;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum LexErrorKind {
+    UnexpectedSymbol,
+    UnfinishedString,
+    UnfinishedLongString,
+    UnfinishedLongComment,
+    InvalidLongStringDelimiter,
+    MalformedNumber,
+    InvalidEscapeSequence,
+    DecimalEscapeTooLarge,
+    HexadecimalDigitExpected,
+    MissingOpeningBrace,
+    MissingClosingBrace,
+    Utf8ValueTooLarge,
+}
+
+// An error found while lexing, pointing at the offending source text
+#[derive(PartialEq, Clone, Debug)]
+pub struct LexError {
+    pub kind: LexErrorKind,
+    pub span: Span,
+    pub text: String,
+}
+
+impl LexError {
+    pub fn new(kind: LexErrorKind, span: Span, text: String) -> Self {
+        Self { kind, span, text }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self.kind {
+            LexErrorKind::UnexpectedSymbol => "unexpected symbol",
+            LexErrorKind::UnfinishedString => "unfinished string",
+            LexErrorKind::UnfinishedLongString => "unfinished long string",
+            LexErrorKind::UnfinishedLongComment => "unfinished long comment",
+            LexErrorKind::InvalidLongStringDelimiter => "invalid long string delimiter",
+            LexErrorKind::MalformedNumber => "malformed number",
+            LexErrorKind::InvalidEscapeSequence => "invalid escape sequence",
+            LexErrorKind::DecimalEscapeTooLarge => "decimal escape too large",
+            LexErrorKind::HexadecimalDigitExpected => "hexadecimal digit expected",
+            LexErrorKind::MissingOpeningBrace => "missing '{' in \\u{xxxx}",
+            LexErrorKind::MissingClosingBrace => "missing '}' in \\u{xxxx}",
+            LexErrorKind::Utf8ValueTooLarge => "UTF-8 value too large",
+        }
+    }
+}
+
+impl Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} near '{}'", self.span, self.message(), self.text)
+    }
+}
+// Walks the source one byte at a time. Tokens can be collected with `tokenize` or pulled one by one
+// through the `Iterator` implementation, which stops after the first error.
 pub struct Lexer<'a> {
-    expr_str: &'a str,
-    current: usize
+    src: &'a [u8],
+    current: usize,
+    line: usize,
+    column: usize,
+    finished: bool,
+    // Only set by `tokenize_lossless`, collects the trivia in front of the next token
+    pending_trivia: Option<Vec<Trivia>>,
 }
 
-const RESERVED_WORDS: [&str; 22] = [
+pub const RESERVED_WORDS: [&str; 22] = [
     "and",
     "break",
     "do",
@@ -56,182 +120,404 @@ const RESERVED_WORDS_TOKENS: [Token; 22] = [
 ];
 
 impl<'a> Lexer<'a> {
-    pub fn new(s: &'a str) -> Self {    
-        return Self { expr_str: s, current: 0 };
+    // Accepts any byte source, Lua source files need not be valid UTF-8
+    pub fn new<S: AsRef<[u8]> + ?Sized>(s: &'a S) -> Self {
+        return Self { src: s.as_ref(), current: 0, line: 1, column: 1, finished: false, pending_trivia: None };
     }
 
-    pub fn tokenize(&mut self) -> Vec<Token> {
-        let mut ret: Vec<Token> = vec![];
-        while self.current < self.expr_str.len() {
-            let c = self.current_char();
-            if c.is_whitespace() {
-                self.advance();
-            } else if c.is_numeric() {
-                ret.push(self.lex_number());
-            } else if self.is_operator(c) {
-                if self.peek_next_char() == Some('-') {
-                    while self.current_char() != '\n' && !self.at_eof() {
-                        self.advance();
-                    }
-                    if self.at_eof() {
-                        return ret;
-                    }
-                    continue;
-                }
-                ret.push(self.lex_operator(c));
-            } else if c == '(' {
-                ret.push(Token::LeftParens);
-                self.advance();
-            } else if c == ')' {
-                ret.push(Token::RightParens);
-                self.advance();
-            } else if c == ',' {
-                ret.push(Token::Comma);
-                self.advance();
-            } else if c.is_alphabetic() ||
-            c == '_' {
-                ret.push(self.lex_identifier());
-            } else if c == ';' {
-                ret.push(Token::Semicolon);
-                self.advance();
-            } else if c == '"' {
-                self.advance();
-                ret.push(self.lex_string('"'));
-            } else if c == '\'' {
+    pub fn tokenize(&mut self) -> Result<Vec<SpannedToken>, LexError> {
+        self.collect()
+    }
+
+    // Lexes the whole source, attaching whitespace and comments to the token that follows them
+    pub fn tokenize_lossless<S: AsRef<[u8]> + ?Sized>(s: &'a S) -> Result<LosslessTokens, LexError> {
+        let mut lexer = Self { pending_trivia: Some(vec![]), ..Self::new(s) };
+        let mut tokens = vec![];
+        while let Some(token) = lexer.next_token()? {
+            let text = lexer.src[token.span.start..token.span.end].to_vec();
+            let leading_trivia = lexer.pending_trivia.replace(vec![]).unwrap_or_default();
+            tokens.push(LosslessToken { token, text, leading_trivia });
+        }
+        let trailing_trivia = lexer.pending_trivia.replace(vec![]).unwrap_or_default();
+        Ok(LosslessTokens { tokens, trailing_trivia })
+    }
+
+    // Lexes the next token, or returns None at the end of the input
+    fn next_token(&mut self) -> Result<Option<SpannedToken>, LexError> {
+        self.skip_trivia()?;
+        let c = match self.current_byte() {
+            Some(c) => c,
+            None => return Ok(None)
+        };
+        let start = self.location();
+        let token = match c {
+            b'0'..=b'9' => self.lex_number(start)?,
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => self.lex_identifier(),
+            b'"' | b'\'' => {
                 self.advance();
-                ret.push(self.lex_string('\''));
-            } else if c == '.' {
-                if let Some(nc) = self.peek_next_char() {
-                    if nc.is_numeric() {
-                        ret.push(self.lex_number());
-                    } else if nc == '.' {
+                self.lex_string(c, start)?
+            },
+            b'.' => {
+                match self.peek_next_byte() {
+                    Some(nc) if nc.is_ascii_digit() => self.lex_number(start)?,
+                    Some(b'.') => {
                         self.advance();
                         self.advance();
-                        if self.current_char() == '.' {
-                            ret.push(Token::Varargs);
+                        if self.current_byte() == Some(b'.') {
+                            self.advance();
+                            Token::Varargs
                         } else {
-                            ret.push(Token::Concatenation);
+                            Token::Concatenation
                         }
-                    } else {
-                        ret.push(Token::Period);
+                    },
+                    _ => {
                         self.advance();
+                        Token::Period
                     }
                 }
-            } else if c == '{' {
-                ret.push(Token::LeftCurlyBrace);
-                self.advance();
-            } else if c == '}' {
-                ret.push(Token::RightCurlyBrace);
-                self.advance();
-            } else if c == '[' {
-                if self.peek_next_char() == Some('[') {
-                    self.advance();
-                    self.advance();
-                    ret.push(self.lex_long_string(0));
-                    continue;
-                } else if self.peek_next_char() == Some('=') {
-                    let mut level = 1;
-                    self.advance();
+            },
+            b'[' => {
+                if let Some(level) = self.opening_long_bracket_level() {
+                    match self.read_long_bracket(level) {
+                        Some(contents) => Token::Literal(Value::String(contents.into())),
+                        None => return Err(self.error(LexErrorKind::UnfinishedLongString, start))
+                    }
+                } else if self.peek_next_byte() == Some(b'=') {
                     self.advance();
-                    while self.current_char() == '=' {
-                        level += 1;
+                    while self.current_byte() == Some(b'=') {
                         self.advance();
                     }
-                    assert!(self.current_char() == '[', "Ill-formed long string");
+                    return Err(self.error(LexErrorKind::InvalidLongStringDelimiter, start));
+                } else {
                     self.advance();
-                    ret.push(self.lex_long_string(level));
-                    continue;
+                    Token::LeftSquareBracket
                 }
-                ret.push(Token::LeftSquareBracket);
+            },
+            b'~' => {
                 self.advance();
-            } else if c == ']' {
-                ret.push(Token::RightSquareBracket);
+                if self.current_byte() == Some(b'=') {
+                    self.advance();
+                    Token::NotEquals
+                } else {
+                    Token::Tilde
+                }
+            },
+            b':' => {
                 self.advance();
-            } else if c == '#' {
-                ret.push(Token::Pound);
+                if self.current_byte() == Some(b':') {
+                    self.advance();
+                    Token::DoubleColon
+                } else {
+                    Token::Colon
+                }
+            },
+            c if self.is_operator(c) => self.lex_operator(c),
+            _ => {
+                let simple = match c {
+                    b'(' => Some(Token::LeftParens),
+                    b')' => Some(Token::RightParens),
+                    b',' => Some(Token::Comma),
+                    b';' => Some(Token::Semicolon),
+                    b'{' => Some(Token::LeftCurlyBrace),
+                    b'}' => Some(Token::RightCurlyBrace),
+                    b']' => Some(Token::RightSquareBracket),
+                    b'#' => Some(Token::Pound),
+                    b'%' => Some(Token::Percent),
+                    _ => None
+                };
                 self.advance();
-            } else if c == '~' {
-                if let Some(next) = self.peek_next_char() {
-                    if next == '=' {
-                        self.advance();
-                        self.advance();
-                        ret.push(Token::NotEquals);
-                    } else {
-                        self.advance();
-                        ret.push(Token::Not);
+                match simple {
+                    Some(token) => token,
+                    None => {
+                        // Report whole UTF-8 sequences rather than a lone leading byte
+                        while matches!(self.current_byte(), Some(b) if b & 0xc0 == 0x80) {
+                            self.advance();
+                        }
+                        return Err(self.error(LexErrorKind::UnexpectedSymbol, start));
                     }
                 }
-            } else if c == '%' {
-                ret.push(Token::Percent);
+            }
+        };
+        Ok(Some(SpannedToken::new(token, self.span_from(start))))
+    }
+
+    // Skips whitespace, comments and the shebang line, recording them in lossless mode
+    fn skip_trivia(&mut self) -> Result<(), LexError> {
+        while let Some(c) = self.current_byte() {
+            let start = self.location();
+            let kind = if c == b'\n' || c == b'\r' {
+                self.skip_newline();
+                TriviaKind::Newline
+            } else if c.is_ascii_whitespace() || c == 0x0b {
+                while matches!(self.current_byte(), Some(c) if c != b'\n' && c != b'\r' && (c.is_ascii_whitespace() || c == 0x0b)) {
+                    self.advance();
+                }
+                TriviaKind::Whitespace
+            } else if c == b'-' && self.peek_next_byte() == Some(b'-') {
                 self.advance();
+                self.advance();
+                self.skip_comment(start)?
+            } else if c == b'#' && self.current == 0 {
+                // Skip the shebang line of executable scripts
+                self.skip_line();
+                TriviaKind::Shebang
             } else {
-                panic!("Cannot lex current sequence. Current char is {}, {} chars have been scanned", self.current_char(), ret.len());
+                break;
+            };
+            if self.pending_trivia.is_some() {
+                let span = self.span_from(start);
+                let text = self.src[span.start..span.end].to_vec();
+                if let Some(pending) = self.pending_trivia.as_mut() {
+                    pending.push(Trivia { kind, span, text });
+                }
             }
         }
-        return ret;
+        Ok(())
+    }
+
+    // Skips a comment whose leading "--" has already been consumed
+    fn skip_comment(&mut self, start: (usize, usize, usize)) -> Result<TriviaKind, LexError> {
+        if let Some(level) = self.opening_long_bracket_level() {
+            if self.read_long_bracket(level).is_none() {
+                return Err(self.error(LexErrorKind::UnfinishedLongComment, start));
+            }
+            return Ok(TriviaKind::BlockComment);
+        }
+        self.skip_line();
+        Ok(TriviaKind::LineComment)
+    }
+
+    // Advances up to, but not past, the end of the current line
+    fn skip_line(&mut self) {
+        while !self.at_eof() && self.current_byte() != Some(b'\n') && self.current_byte() != Some(b'\r') {
+            self.advance();
+        }
     }
 
-    fn lex_long_string(&mut self, level: usize) -> Token {
+    // If the lexer is at an opening long bracket such as "[[" or "[==[", returns its level without consuming anything
+    fn opening_long_bracket_level(&self) -> Option<usize> {
+        if self.current_byte() != Some(b'[') {
+            return None;
+        }
+        let mut level = 0;
+        loop {
+            match self.peek_byte(level + 1) {
+                Some(b'=') => level += 1,
+                Some(b'[') => return Some(level),
+                _ => return None
+            }
+        }
+    }
+
+    // Reads the contents of a long string or comment, starting at its opening bracket.
+    // Returns None if the input ends before the closing bracket.
+    fn read_long_bracket(&mut self, level: usize) -> Option<Vec<u8>> {
+        for _ in 0..level + 2 {
+            self.advance();
+        }
+        // A newline right after the opening bracket is not part of the string
+        self.skip_newline();
         let scan_start = self.current;
         while !self.at_eof() {
-            while self.current_char() != ']' && self.current < self.expr_str.len() {
+            while self.current_byte() != Some(b']') && !self.at_eof() {
                 self.advance();
             }
             if self.at_eof() {
-                panic!("Ill formed long string");
+                break;
             }
             let mut closing_level: usize = 0;
             self.advance();
-            while self.current_char() == '=' && closing_level < level {
+            while self.current_byte() == Some(b'=') && closing_level < level {
                 closing_level += 1;
                 self.advance();
             }
-            if self.current_char() == ']' && level == closing_level {
-                let ret = Token::Literal(Value::String(self.expr_str[scan_start..self.current - (1 + closing_level)].into()));
+            if self.current_byte() == Some(b']') && level == closing_level {
+                let contents = normalize_newlines(&self.src[scan_start..self.current - (1 + closing_level)]);
                 self.advance();
-                return ret;
+                return Some(contents);
             }
         }
-        panic!("Ill formed long string");
+        None
     }
 
-    fn lex_string(&mut self, string_limiter: char) -> Token {
-        let scan_start = self.current;
-        while self.current_char() != string_limiter && self.current < self.expr_str.len() {
-            self.advance();
+    fn lex_string(&mut self, string_limiter: u8, start: (usize, usize, usize)) -> Result<Token, LexError> {
+        let mut bytes: Vec<u8> = vec![];
+        while self.current_byte() != Some(string_limiter) {
+            match self.current_byte() {
+                None | Some(b'\n') | Some(b'\r') => return Err(self.error(LexErrorKind::UnfinishedString, start)),
+                Some(b'\\') => {
+                    self.advance();
+                    self.lex_escape(&mut bytes, start)?;
+                },
+                Some(c) => {
+                    bytes.push(c);
+                    self.advance();
+                }
+            }
         }
-        assert!(self.current_char() == string_limiter, "Missing closing quote");
-        let ret = Token::Literal(Value::String(self.expr_str[scan_start..self.current].into()));
+        let ret = Token::Literal(Value::String(bytes.into()));
         self.advance();
-        return ret;
+        return Ok(ret);
+    }
+
+    // Lexes the escape sequence following a backslash, which has already been consumed
+    fn lex_escape(&mut self, bytes: &mut Vec<u8>, start: (usize, usize, usize)) -> Result<(), LexError> {
+        let c = match self.current_byte() {
+            Some(c) => c,
+            None => return Err(self.error(LexErrorKind::UnfinishedString, start))
+        };
+        let simple = match c {
+            b'a' => Some(0x07),
+            b'b' => Some(0x08),
+            b'f' => Some(0x0c),
+            b'n' => Some(b'\n'),
+            b'r' => Some(b'\r'),
+            b't' => Some(b'\t'),
+            b'v' => Some(0x0b),
+            b'\\' => Some(b'\\'),
+            b'"' => Some(b'"'),
+            b'\'' => Some(b'\''),
+            _ => None
+        };
+        if let Some(b) = simple {
+            bytes.push(b);
+            self.advance();
+            return Ok(());
+        }
+        match c {
+            b'\n' | b'\r' => {
+                self.skip_newline();
+                bytes.push(b'\n');
+            },
+            b'z' => {
+                self.advance();
+                while matches!(self.current_byte(), Some(c) if c.is_ascii_whitespace() || c == 0x0b) {
+                    self.advance();
+                }
+            },
+            b'x' => {
+                self.advance();
+                let mut value: u8 = 0;
+                for _ in 0..2 {
+                    let digit = self.current_byte().and_then(|c| (c as char).to_digit(16));
+                    self.advance();
+                    match digit {
+                        Some(d) => value = value * 16 + d as u8,
+                        None => return Err(self.error(LexErrorKind::HexadecimalDigitExpected, start))
+                    }
+                }
+                bytes.push(value);
+            },
+            b'u' => {
+                self.advance();
+                if self.current_byte() != Some(b'{') {
+                    self.advance();
+                    return Err(self.error(LexErrorKind::MissingOpeningBrace, start));
+                }
+                self.advance();
+                let mut value: u32 = 0;
+                let mut digits = 0;
+                while let Some(d) = self.current_byte().and_then(|c| (c as char).to_digit(16)) {
+                    self.advance();
+                    digits += 1;
+                    if value >= 0x8000000 {
+                        return Err(self.error(LexErrorKind::Utf8ValueTooLarge, start));
+                    }
+                    value = value * 16 + d;
+                }
+                if digits == 0 {
+                    self.advance();
+                    return Err(self.error(LexErrorKind::HexadecimalDigitExpected, start));
+                }
+                if self.current_byte() != Some(b'}') {
+                    self.advance();
+                    return Err(self.error(LexErrorKind::MissingClosingBrace, start));
+                }
+                self.advance();
+                encode_utf8(value, bytes);
+            },
+            c if c.is_ascii_digit() => {
+                let mut value: u32 = 0;
+                for _ in 0..3 {
+                    match self.current_byte() {
+                        Some(d) if d.is_ascii_digit() => {
+                            value = value * 10 + (d - b'0') as u32;
+                            self.advance();
+                        },
+                        _ => break
+                    }
+                }
+                if value > 255 {
+                    return Err(self.error(LexErrorKind::DecimalEscapeTooLarge, start));
+                }
+                bytes.push(value as u8);
+            },
+            _ => {
+                self.advance();
+                return Err(self.error(LexErrorKind::InvalidEscapeSequence, start));
+            }
+        }
+        Ok(())
     }
 
-    fn lex_number(&mut self) -> Token {
+    // Skips one line break, if the lexer is at one
+    fn skip_newline(&mut self) {
+        if matches!(self.current_byte(), Some(b'\n') | Some(b'\r')) {
+            self.advance();
+        }
+    }
+
+    // Scans a numeral the same way the reference implementation does: greedily take every hex digit,
+    // dot and exponent (with its sign), then let `parse_numeral` decide whether the result is valid
+    fn lex_number(&mut self, start: (usize, usize, usize)) -> Result<Token, LexError> {
         let scan_start = self.current;
-        while self.current < self.expr_str.len() {
-            let c = self.current_char();
-            if c.is_numeric() || c == '.' {
+        let mut exponent_marks = [b'e', b'E'];
+        if self.current_byte() == Some(b'0') && matches!(self.peek_next_byte(), Some(b'x') | Some(b'X')) {
+            self.advance();
+            self.advance();
+            exponent_marks = [b'p', b'P'];
+        }
+        while let Some(c) = self.current_byte() {
+            if exponent_marks.contains(&c) {
                 self.advance();
-                continue;
+                if matches!(self.current_byte(), Some(b'+') | Some(b'-')) {
+                    self.advance();
+                }
+            } else if c.is_ascii_hexdigit() || c == b'.' {
+                self.advance();
+            } else {
+                break;
             }
-            break;
-        };
-            return Token::Literal(Value::Number(self.expr_str[scan_start..self.current].parse().expect("lex_number(): Above code should ensure a valid scan")));
+        }
+        // A numeral touching a letter is an error, e.g. `3x`
+        if let Some(c) = self.current_byte() {
+            if c.is_ascii_alphanumeric() || c == b'_' {
+                self.advance();
+                return Err(self.error(LexErrorKind::MalformedNumber, start));
+            }
+        }
+        // Every byte scanned above is ASCII
+        let numeral = std::str::from_utf8(&self.src[scan_start..self.current]).unwrap_or("");
+        match parse_numeral(numeral) {
+            Some(n) => Ok(Token::Literal(n)),
+            None => Err(self.error(LexErrorKind::MalformedNumber, start))
+        }
     }
 
     fn lex_identifier(&mut self) -> Token {
         let scan_start = self.current;
-        while self.current < self.expr_str.len() {
-            let c = self.current_char();
-            if c.is_alphanumeric() ||
-             c == '_' {
+        while let Some(c) = self.current_byte() {
+            if c.is_ascii_alphanumeric() ||
+             c == b'_' {
                 self.advance();
                 continue;
             }
             break;
         };
 
-        if let Ok(r_idx) = RESERVED_WORDS.binary_search(&&self.expr_str[scan_start..self.current]) {
+        // Identifiers are ASCII only, so this cannot fail
+        let name = std::str::from_utf8(&self.src[scan_start..self.current]).unwrap_or("");
+        if let Ok(r_idx) = RESERVED_WORDS.binary_search(&name) {
             return match r_idx {
                 6 => Token::Literal(Value::Boolean(false)),
                 13 => Token::Literal(Value::Nil),
@@ -239,76 +525,353 @@ impl<'a> Lexer<'a> {
                 _ => RESERVED_WORDS_TOKENS[r_idx].clone()
             }
         }
-        return Token::Identifier(self.expr_str[scan_start..self.current].into());
+        return Token::Identifier(name.into());
     }
 
-    fn lex_operator(&mut self, c: char) -> Token {
-         match c {
-            '+' => {self.advance(); Token::Plus},
-            '-' => {self.advance(); Token::Minus},
-            '/' => {self.advance(); Token::ForwardSlash},
-            '*' => {self.advance(); Token::Star},
-            '=' => {
-                self.advance();
-                if self.current_char() == '=' {
+    // Only called with characters accepted by `is_operator`
+    fn lex_operator(&mut self, c: u8) -> Token {
+        self.advance();
+        match c {
+            b'+' => Token::Plus,
+            b'-' => Token::Minus,
+            b'*' => Token::Star,
+            b'^' => Token::Caret,
+            b'&' => Token::Ampersand,
+            b'|' => Token::Pipe,
+            b'/' => {
+                if self.current_byte() == Some(b'/') {
+                    self.advance();
+                    return Token::DoubleForwardSlash;
+                }
+                return Token::ForwardSlash;
+            },
+            b'=' => {
+                if self.current_byte() == Some(b'=') {
                     self.advance();
                     return Token::Equals;
                 }
                 return Token::Assign;
             },
-            '<' => {
-                self.advance();
-                if self.current_char() == '=' {
+            b'<' => {
+                if self.current_byte() == Some(b'=') {
                     self.advance();
                     return Token::LessThanOrEqual;
+                } else if self.current_byte() == Some(b'<') {
+                    self.advance();
+                    return Token::ShiftLeft;
                 }
                 return Token::LessThan;
             },
-            '>' => {
-                self.advance();
-                if self.current_char() == '=' {
+            _ => {
+                if self.current_byte() == Some(b'=') {
                     self.advance();
                     return Token::GreaterThanOrEqual;
+                } else if self.current_byte() == Some(b'>') {
+                    self.advance();
+                    return Token::ShiftRight;
                 }
                 return Token::GreaterThan;
             }
-            _ => panic!("Unknown symbol")
         }
     }
 
-    fn is_operator(&self, c: char) -> bool {
+    fn is_operator(&self, c: u8) -> bool {
         match c {
-            '+' => true,
-            '-' => true,
-            '/' => true,
-            '*' => true,
-            '<' => true,
-            '>' => true,
-            '=' => true,
+            b'+' => true,
+            b'-' => true,
+            b'/' => true,
+            b'*' => true,
+            b'^' => true,
+            b'&' => true,
+            b'|' => true,
+            b'<' => true,
+            b'>' => true,
+            b'=' => true,
             _ => false
         }
     }
 
+    // Builds an error covering everything scanned since `start`
+    fn error(&mut self, kind: LexErrorKind, start: (usize, usize, usize)) -> LexError {
+        self.finished = true;
+        let span = self.span_from(start);
+        LexError::new(kind, span, String::from_utf8_lossy(&self.src[span.start..span.end]).into_owned())
+    }
+
+    // Current byte offset, line and column of the lexer
+    fn location(&self) -> (usize, usize, usize) {
+        (self.current, self.line, self.column)
+    }
+
+    fn span_from(&self, start: (usize, usize, usize)) -> Span {
+        let (start, line, column) = start;
+        Span::new(start, self.current, line, column, self.line, self.column)
+    }
+
+    // Columns count characters, so UTF-8 continuation bytes do not move the column.
+    // A line break is consumed whole, treating "\r\n" and "\n\r" as a single one
     fn advance(&mut self) {
-        if self.current < self.expr_str.len() {
+        if let Some(c) = self.current_byte() {
             self.current += 1;
-        } else {
-            panic!("Lexer internal error: reading past end of buffer");
+            if c == b'\n' || c == b'\r' {
+                if matches!(self.current_byte(), Some(next) if (next == b'\n' || next == b'\r') && next != c) {
+                    self.current += 1;
+                }
+                self.line += 1;
+                self.column = 1;
+            } else if c & 0xc0 != 0x80 {
+                self.column += 1;
+            }
         }
-    } 
+    }
 
-    fn current_char(&self) -> char {
-        return self.expr_str.chars().nth(self.current).expect("Lexer should not be out of bounds");
+    fn current_byte(&self) -> Option<u8> {
+        self.src.get(self.current).copied()
     }
 
     fn at_eof(&self) -> bool {
-        return self.current == (self.expr_str.len() - 1);
+        return self.current >= self.src.len();
     }
 
-    fn peek_next_char(&self) -> Option<char> {
-        if self.at_eof() {
+    fn peek_next_byte(&self) -> Option<u8> {
+        self.peek_byte(1)
+    }
+
+    fn peek_byte(&self, offset: usize) -> Option<u8> {
+        self.src.get(self.current + offset).copied()
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<SpannedToken, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
             return None;
         }
-        return self.expr_str.chars().nth(self.current + 1);
+        match self.next_token() {
+            Ok(Some(token)) => Some(Ok(token)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            },
+            Err(err) => Some(Err(err))
+        }
+    }
+}
+
+// Converts every line break sequence of a long string into "\n"
+fn normalize_newlines(s: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(s.len());
+    let mut bytes = s.iter().copied().peekable();
+    while let Some(c) = bytes.next() {
+        if c == b'\n' || c == b'\r' {
+            if let Some(&next) = bytes.peek() {
+                if (next == b'\n' || next == b'\r') && next != c {
+                    bytes.next();
+                }
+            }
+            ret.push(b'\n');
+        } else {
+            ret.push(c);
+        }
+    }
+    ret
+}
+
+// Encodes code points up to 2^31 using the original, up to six byte long, UTF-8 scheme
+fn encode_utf8(value: u32, bytes: &mut Vec<u8>) {
+    if value < 0x80 {
+        bytes.push(value as u8);
+        return;
+    }
+    let mut continuation: Vec<u8> = vec![];
+    let mut value = value;
+    // Largest value that fits in the first byte for the current sequence length
+    let mut first_byte_max: u32 = 0x3f;
+    while value > first_byte_max {
+        continuation.push(0x80 | (value & 0x3f) as u8);
+        value >>= 6;
+        first_byte_max >>= 1;
+    }
+    let prefix: u8 = !(first_byte_max as u8) << 1;
+    bytes.push(prefix | value as u8);
+    bytes.extend(continuation.iter().rev());
+}
+
+// Converts a Lua numeral into an integer or float value. Decimal integers that do not fit in an
+// integer become floats, while hexadecimal integers wrap around, as in the reference implementation.
+pub fn parse_numeral(s: &str) -> Option<Value> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return parse_hex_numeral(hex);
+    }
+    if !s.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    if !s.contains(['.', 'e', 'E']) {
+        if let Ok(i) = s.parse::<i64>() {
+            return Some(Value::Integer(i));
+        }
+    }
+    // Rust's float grammar is a superset of Lua's decimal one, so reject the words it accepts
+    if s.contains(|c: char| c.is_ascii_alphabetic() && c != 'e' && c != 'E') {
+        return None;
+    }
+    s.parse::<f64>().ok().map(|f| Value::Number(OrderedFloat(f)))
+}
+
+fn parse_hex_numeral(s: &str) -> Option<Value> {
+    let (mantissa, exponent) = match s.find(['p', 'P']) {
+        Some(idx) => (&s[..idx], Some(&s[idx + 1..])),
+        None => (s, None)
+    };
+    let (int_part, frac_part) = match mantissa.find('.') {
+        Some(idx) => (&mantissa[..idx], Some(&mantissa[idx + 1..])),
+        None => (mantissa, None)
+    };
+    let frac_digits = frac_part.unwrap_or("");
+    if int_part.is_empty() && frac_digits.is_empty() {
+        return None;
+    }
+    if !int_part.chars().chain(frac_digits.chars()).all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    if frac_part.is_none() && exponent.is_none() {
+        let mut i: i64 = 0;
+        for c in int_part.chars() {
+            i = i.wrapping_mul(16).wrapping_add(c.to_digit(16)? as i64);
+        }
+        return Some(Value::Integer(i));
+    }
+    let mut f: f64 = 0.0;
+    for c in int_part.chars().chain(frac_digits.chars()) {
+        f = f * 16.0 + c.to_digit(16)? as f64;
+    }
+    let mut binary_exponent: i32 = -4 * frac_digits.len() as i32;
+    if let Some(exponent) = exponent {
+        let digits = exponent.strip_prefix(|c: char| c == '+' || c == '-').unwrap_or(exponent);
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let e: i32 = digits.parse().unwrap_or(i32::MAX);
+        binary_exponent = binary_exponent.saturating_add(if exponent.starts_with('-') { -e } else { e });
+    }
+    Some(Value::Number(OrderedFloat(f * 2f64.powi(binary_exponent))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex_error(src: &str) -> LexError {
+        Lexer::new(src).tokenize().err().expect("source should not lex")
+    }
+
+    #[test]
+    fn error_kinds() {
+        let cases = [
+            ("x = $", LexErrorKind::UnexpectedSymbol),
+            ("x = 'abc", LexErrorKind::UnfinishedString),
+            ("x = \"abc\ny\"", LexErrorKind::UnfinishedString),
+            ("x = [[abc", LexErrorKind::UnfinishedLongString),
+            ("--[==[ abc ]]", LexErrorKind::UnfinishedLongComment),
+            ("x = [=abc", LexErrorKind::InvalidLongStringDelimiter),
+            ("x = 3x", LexErrorKind::MalformedNumber),
+            ("x = 1e", LexErrorKind::MalformedNumber),
+            ("x = '\\q'", LexErrorKind::InvalidEscapeSequence),
+            ("x = '\\256'", LexErrorKind::DecimalEscapeTooLarge),
+            ("x = '\\xg0'", LexErrorKind::HexadecimalDigitExpected),
+            ("x = '\\u123'", LexErrorKind::MissingOpeningBrace),
+            ("x = '\\u{123'", LexErrorKind::MissingClosingBrace),
+            ("x = '\\u{80000000}'", LexErrorKind::Utf8ValueTooLarge),
+        ];
+        for (src, kind) in cases {
+            assert_eq!(lex_error(src).kind, kind, "{src}");
+        }
+    }
+
+    #[test]
+    fn error_spans() {
+        let err = lex_error("x = 1\ny = 3x");
+        assert_eq!(err.span, Span::new(10, 12, 2, 5, 2, 7));
+        assert_eq!(err.text, "3x");
+        assert_eq!(err.to_string(), "2:5: malformed number near '3x'");
+
+        let err = lex_error("s = 'a\\qb'");
+        assert_eq!(err.span, Span::new(4, 8, 1, 5, 1, 9));
+        assert_eq!(err.to_string(), "1:5: invalid escape sequence near ''a\\q'");
+
+        // The whole UTF-8 sequence of an unexpected character is reported
+        let err = lex_error("x = é");
+        assert_eq!(err.span, Span::new(4, 6, 1, 5, 1, 6));
+        assert_eq!(err.text, "é");
+    }
+
+    #[test]
+    fn line_breaks() {
+        // "\r\n" and "\n\r" are one line break, two equal characters are two
+        let tokens = Lexer::new("a\rb\n\rc\r\nd\n\ne --[[\r\r]] [[\n\r]] f").tokenize().expect("source should lex");
+        let lines: Vec<usize> = tokens.iter().map(|t| t.span.line).collect();
+        assert_eq!(lines, [1, 2, 3, 4, 6, 8, 9]);
+        assert_eq!(tokens[6].span, Span::new(29, 30, 9, 4, 9, 5));
+    }
+
+    #[test]
+    fn iterator_streams_tokens() {
+        let mut lexer = Lexer::new("local x = 1");
+        let first = lexer.next().and_then(|t| t.ok()).expect("a token");
+        assert!(first.token == Token::Local);
+        assert_eq!(first.span, Span::new(0, 5, 1, 1, 1, 6));
+        assert_eq!(lexer.by_ref().count(), 3);
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn iterator_stops_after_error() {
+        let mut lexer = Lexer::new("a = 1 $ b = 2");
+        let items: Vec<Result<SpannedToken, LexError>> = lexer.by_ref().collect();
+        assert_eq!(items.len(), 4);
+        assert!(items[..3].iter().all(|t| t.is_ok()));
+        let err = items[3].clone().err().expect("an error");
+        assert_eq!(err.kind, LexErrorKind::UnexpectedSymbol);
+        assert_eq!(err.span, Span::new(6, 7, 1, 7, 1, 8));
+        // Nothing follows the error, not even the tokens after it
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn lossless_round_trip() {
+        let sources = [
+            "#!/usr/bin/env lua\nprint(1)\n",
+            "  local\tx =\r\n  1 -- trailing comment\n\n",
+            "--[==[ long\n]] comment ]==] x = [[\nlong\rstring]] .. [=[ ]] ]=]",
+            "-- only a comment",
+            "",
+            "f{ 'a' ;\x0b\"b\" }  ",
+        ];
+        for src in sources {
+            let tokens = Lexer::tokenize_lossless(src).expect("source should lex");
+            assert_eq!(tokens.to_source(), src.as_bytes());
+        }
+    }
+
+    #[test]
+    fn lossless_round_trip_keeps_bytes() {
+        let src: &[u8] = b"-- caf\xe9\nx = '\xff\xfe' --[[ \x80 ]]\n";
+        let tokens = Lexer::tokenize_lossless(src).expect("source should lex");
+        assert_eq!(tokens.to_source(), src);
+        assert_eq!(tokens.tokens[0].leading_trivia[0].text, b"-- caf\xe9");
+        assert_eq!(tokens.tokens[2].text, b"'\xff\xfe'");
+    }
+
+    #[test]
+    fn lossless_trivia() {
+        let tokens = Lexer::tokenize_lossless("#!lua\n--[[a]] x -- b\n").expect("source should lex");
+        let kinds: Vec<TriviaKind> = tokens.tokens[0].leading_trivia.iter().map(|t| t.kind).collect();
+        assert_eq!(kinds, [TriviaKind::Shebang, TriviaKind::Newline, TriviaKind::BlockComment, TriviaKind::Whitespace]);
+        assert_eq!(tokens.tokens[0].text, b"x");
+        let kinds: Vec<TriviaKind> = tokens.trailing_trivia.iter().map(|t| t.kind).collect();
+        assert_eq!(kinds, [TriviaKind::Whitespace, TriviaKind::LineComment, TriviaKind::Newline]);
+        assert_eq!(tokens.trailing_trivia[1].span, Span::new(16, 20, 2, 11, 2, 15));
     }
 }
@@ -1,14 +1,24 @@
 pub mod tokens;
+pub mod span;
 pub mod expr;
 pub mod parser;
 pub mod lexer;
 pub mod interpreter;
 pub mod stmt;
 pub mod values;
+pub mod lua_string;
 pub mod function;
 pub mod table;
 pub mod native_function;
 pub mod gc;
+pub mod highlight;
+pub mod format;
+pub mod dump;
+pub mod labels;
+pub mod visitor;
+pub mod resolver;
+pub mod optimizer;
+pub mod arith;
 
 use interpreter::Interpreter;
 use tokens::Token;
@@ -18,22 +28,40 @@ use lexer::Lexer;
 use stmt::Stmt;
 use values::Value;
 
-pub fn exec_script(script: String) {
+// Scripts are plain bytes, Lua strings inside them may hold any byte sequence
+pub fn exec_script(script: impl AsRef<[u8]>) {
     let mut interp = Interpreter::new();
     exec_repl(script, &mut interp);
 }
 
-pub fn exec_repl(expr: String, interp: &mut Interpreter) {
-    let mut lexer = Lexer::new(expr.as_str());
-    let tokens = lexer.tokenize();
+pub fn exec_repl(expr: impl AsRef<[u8]>, interp: &mut Interpreter) {
+    let mut lexer = Lexer::new(expr.as_ref());
+    let tokens = match lexer.tokenize() {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            println!("Syntax error: {err}");
+            return;
+        }
+    };
     let mut parser = Parser::new(tokens);
-    let chunk = parser.chunk();
-    if let Ok(chunk) = chunk {
-        if let Err(err) = interp.eval_stmt(&chunk) {
+    let (mut chunk, mut errors) = parser.chunk_with_errors();
+    // Resolved before optimizing, so that removing dead code cannot hide an error.
+    // The optimizer keeps the variables and functions it leaves in place as they were resolved
+    let mut main = None;
+    if errors.is_empty() {
+        match resolver::resolve(&mut chunk) {
+            Ok(info) => main = Some(info),
+            Err(resolve_errors) => errors = resolve_errors,
+        }
+    }
+    if let Some(main) = main {
+        let chunk = if interp.optimize_enabled() { optimizer::optimize(chunk) } else { chunk };
+        if let Err(err) = interp.exec_chunk(&chunk, &main) {
             println!("{err}");
         }
-    } else if let Err(s) = chunk {
-        println!("Error parsing: {s}");
+    }
+    for err in errors.iter() {
+        println!("Error parsing: {err}");
     }
 }
 
@@ -42,5 +70,5 @@ use wasm_bindgen::prelude::wasm_bindgen;
 #[cfg(target_family = "wasm")]
 #[wasm_bindgen]
 pub fn execute(blob: &str) {
-    exec_script(blob.into())
+    exec_script(blob)
 }
\ No newline at end of file
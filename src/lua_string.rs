@@ -0,0 +1,70 @@
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::rc::Rc;
+
+// A Lua string: an immutable sequence of bytes with no encoding attached.
+// Only converted to text (lossily) when it leaves the interpreter, e.g. when printed
+#[derive(PartialEq, Eq, Hash, Clone, PartialOrd, Ord, Debug)]
+pub struct LuaString(Rc<[u8]>);
+
+impl LuaString {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    // Length in bytes, which is what `#` returns
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn concat(&self, other: &LuaString) -> LuaString {
+        let mut bytes = Vec::with_capacity(self.len() + other.len());
+        bytes.extend_from_slice(&self.0);
+        bytes.extend_from_slice(&other.0);
+        bytes.into()
+    }
+
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(bytes: &[u8]) -> Self {
+        LuaString(bytes.into())
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    fn from(bytes: Vec<u8>) -> Self {
+        LuaString(bytes.into())
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(s: &str) -> Self {
+        s.as_bytes().into()
+    }
+}
+
+impl From<String> for LuaString {
+    fn from(s: String) -> Self {
+        s.into_bytes().into()
+    }
+}
+
+impl From<&String> for LuaString {
+    fn from(s: &String) -> Self {
+        s.as_str().into()
+    }
+}
+
+impl Display for LuaString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_string_lossy())
+    }
+}
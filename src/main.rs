@@ -1,11 +1,189 @@
-use redmoon::{interpreter::Interpreter, lexer::Lexer, parser::Parser, exec_repl, exec_script};
+use redmoon::{interpreter::Interpreter, lexer::Lexer, parser::Parser, exec_repl, highlight::{highlight, HighlightFormat}, format::{format, FormatOptions, QuoteStyle}, dump::{dump_tokens, dump_ast, DumpFormat}};
+use std::io::Write;
+
+// Usage: redmoon highlight <file> [--format ansi|html]
+fn highlight_command(args: &[String]) {
+    let mut file = None;
+    let mut format = HighlightFormat::Ansi;
+    let mut iter = args.iter();
+    while let Some(a) = iter.next() {
+        if a == "--format" {
+            match iter.next().and_then(|name| HighlightFormat::from_name(name)) {
+                Some(f) => format = f,
+                None => {
+                    println!("--format expects \"ansi\" or \"html\"");
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            file = Some(a);
+        }
+    }
+    let Some(file) = file else {
+        println!("Usage: redmoon highlight <file> [--format ansi|html]");
+        std::process::exit(1);
+    };
+    if let Ok(f) = std::fs::read(file) {
+        match highlight(&f, format) {
+            Ok(out) => {
+                let _ = std::io::stdout().write_all(&out);
+            },
+            Err(err) => {
+                println!("Syntax error: {err}");
+                std::process::exit(1);
+            }
+        }
+    } else {
+        println!("File {file} does not exist");
+        std::process::exit(1);
+    }
+}
+
+// Usage: redmoon fmt [--check] [--indent <width>] [--quotes double|single|preserve] [--line-width <width>] <files...>
+// Rewrites the files in place, or with --check only reports the ones that are not formatted
+fn fmt_command(args: &[String]) {
+    let usage = "Usage: redmoon fmt [--check] [--indent <width>] [--quotes double|single|preserve] [--line-width <width>] <files...>";
+    let mut files = vec![];
+    let mut options = FormatOptions::default();
+    let mut check = false;
+    let mut iter = args.iter();
+    while let Some(a) = iter.next() {
+        match a.as_str() {
+            "--check" => check = true,
+            "--indent" | "--line-width" => {
+                let Some(width) = iter.next().and_then(|w| w.parse::<usize>().ok()) else {
+                    println!("{a} expects a number");
+                    std::process::exit(1);
+                };
+                if a == "--indent" {
+                    options.indent_width = width;
+                } else {
+                    options.line_width = width;
+                }
+            },
+            "--quotes" => match iter.next().and_then(|name| QuoteStyle::from_name(name)) {
+                Some(style) => options.quote_style = style,
+                None => {
+                    println!("--quotes expects \"double\", \"single\" or \"preserve\"");
+                    std::process::exit(1);
+                }
+            },
+            _ => files.push(a),
+        }
+    }
+    if files.is_empty() {
+        println!("{usage}");
+        std::process::exit(1);
+    }
+    let mut failed = false;
+    for file in files {
+        let Ok(src) = std::fs::read(file) else {
+            println!("File {file} does not exist");
+            failed = true;
+            continue;
+        };
+        match format(&src, &options) {
+            Ok(out) if out == src => {},
+            Ok(_) if check => {
+                println!("{file} is not formatted");
+                failed = true;
+            },
+            Ok(out) => {
+                if std::fs::write(file, out).is_err() {
+                    println!("Could not write {file}");
+                    failed = true;
+                }
+            },
+            Err(err) => {
+                println!("{file}: {err}");
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+// Usage: redmoon --dump-tokens <file>
+//        redmoon --dump-ast <file> [--format sexpr|json]
+// Prints what the lexer or the parser make of a script, without running it
+fn dump_command(args: &[String]) {
+    let usage = "Usage: redmoon --dump-tokens <file>\n       redmoon --dump-ast <file> [--format sexpr|json]";
+    let mut file = None;
+    let mut tokens = false;
+    let mut format = DumpFormat::Sexpr;
+    let mut iter = args.iter();
+    while let Some(a) = iter.next() {
+        match a.as_str() {
+            "--dump-tokens" => tokens = true,
+            "--dump-ast" => {},
+            "--format" => match iter.next().and_then(|name| DumpFormat::from_name(name)) {
+                Some(f) => format = f,
+                None => {
+                    println!("--format expects \"sexpr\" or \"json\"");
+                    std::process::exit(1);
+                }
+            },
+            _ => file = Some(a),
+        }
+    }
+    let Some(file) = file else {
+        println!("{usage}");
+        std::process::exit(1);
+    };
+    let Ok(src) = std::fs::read(file) else {
+        println!("File {file} does not exist");
+        std::process::exit(1);
+    };
+    if tokens {
+        match dump_tokens(&src) {
+            Ok(out) => print!("{out}"),
+            Err(err) => {
+                println!("Syntax error: {err}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let parsed = Lexer::new(&src).tokenize()
+        .map_err(|err| vec![format!("Syntax error: {err}")])
+        .and_then(|tokens| {
+            let (chunk, errors) = Parser::new(tokens).chunk_with_errors();
+            if errors.is_empty() { Ok(chunk) } else { Err(errors.iter().map(|e| format!("Error parsing: {e}")).collect()) }
+        });
+    match parsed {
+        Ok(chunk) => print!("{}", dump_ast(&chunk, format)),
+        Err(errors) => {
+            errors.iter().for_each(|e| println!("{e}"));
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main() {
     let mut interp = Interpreter::new();
     let args: Vec<String> = std::env::args().collect();
-    if let Some(a) = args.get(1) {
+    if args.get(1).map(|a| a.as_str()) == Some("highlight") {
+        highlight_command(&args[2..]);
+        return;
+    }
+    if args.iter().any(|a| a == "--dump-tokens" || a == "--dump-ast") {
+        dump_command(&args[1..]);
+        return;
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("fmt") {
+        fmt_command(&args[2..]);
+        return;
+    }
+    // Usage: redmoon [--no-optimize] [file]
+    let mut script_args = args[1..].iter().filter(|a| *a != "--no-optimize");
+    if args.iter().any(|a| a == "--no-optimize") {
+        interp.set_optimize(false);
+    }
+    if let Some(a) = script_args.next() {
         if let Ok(f) = std::fs::read(a) {
-            let buffer: String =  String::from_utf8_lossy(&f).to_string();
-            exec_script(buffer);
+            exec_repl(f, &mut interp);
         } else {
             println!("File {a} does not exist");
         }
@@ -2,7 +2,8 @@ use std::rc::Rc;
 use core::hash::Hash;
 use crate::{Interpreter, Value};
 
-pub type NativeFunctionImpl = dyn Fn(&mut Interpreter, &mut Vec<Value>) -> Option<Value>;
+// Returns the results of the call, None when there are none, or the message of a runtime error
+pub type NativeFunctionImpl = dyn Fn(&mut Interpreter, &mut Vec<Value>) -> Result<Option<Value>, String>;
 
 pub struct NativeFunction {
     nfi: Rc<Box<NativeFunctionImpl>>
@@ -35,7 +36,7 @@ impl NativeFunction {
     pub fn new(closure: Box<NativeFunctionImpl>) -> Self {
         Self { nfi: Rc::new(closure) }
     }
-    pub fn call(&self, interp: &mut Interpreter, args: &mut Vec<Value>) -> Option<Value> {
+    pub fn call(&self, interp: &mut Interpreter, args: &mut Vec<Value>) -> Result<Option<Value>, String> {
         self.nfi.as_ref().as_ref()(interp, args)
     }
 }
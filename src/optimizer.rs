@@ -0,0 +1,151 @@
+use std::cmp::Ordering;
+use ordered_float::OrderedFloat;
+use crate::{Expr, Stmt, Token, Value, expr::ExprKind, stmt::StmtKind};
+use crate::arith::{compare_values, is_truthy, raw_equals, shift_left, to_float, to_integer};
+use crate::visitor::{Folder, walk_fold_expr, walk_fold_stmt};
+
+// Folds constant arithmetic, comparisons and concatenations and drops branches that can never run.
+// Only operations on constants that cannot raise an error are folded (no division by zero, no string
+// coercion, no metamethods), everything else is left to the interpreter.
+pub fn optimize(chunk: Stmt) -> Stmt {
+    Optimizer.fold_stmt(chunk)
+}
+
+struct Optimizer;
+
+impl Folder for Optimizer {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        let expr = walk_fold_expr(self, expr);
+        let folded = match &expr.kind {
+            ExprKind::Binary(left, op, right) => fold_binary(left, op, right),
+            ExprKind::Unary(operand, op) => constant(operand).and_then(|v| fold_unary(op, v)),
+            // A parenthesized constant is still a single value
+            ExprKind::Grouping(inner) => constant(inner).cloned(),
+            _ => None,
+        };
+        match folded {
+            Some(value) => Expr::new(ExprKind::Literal(value), expr.span),
+            None => expr,
+        }
+    }
+
+    fn fold_stmt(&mut self, stmt: Stmt) -> Stmt {
+        let stmt = walk_fold_stmt(self, stmt);
+        match stmt.kind {
+            StmtKind::IfStmt(cond, body, else_body) => match constant(&cond).map(is_truthy) {
+                Some(true) => scoped(*body),
+                Some(false) => scoped(*else_body),
+                None => Stmt::new(StmtKind::IfStmt(cond, body, else_body), stmt.span),
+            },
+            StmtKind::WhileLoop(cond, _) if constant(&cond).is_some_and(|v| !is_truthy(v)) => Stmt::new(StmtKind::Empty, stmt.span),
+            kind => Stmt::new(kind, stmt.span),
+        }
+    }
+}
+
+// The branch of an `if` keeps its own scope once the `if` around it is gone
+fn scoped(body: Stmt) -> Stmt {
+    match body.kind {
+        StmtKind::Block(stmts) => Stmt::new(StmtKind::DoBlock(stmts), body.span),
+        _ => body,
+    }
+}
+
+fn constant(expr: &Expr) -> Option<&Value> {
+    match &expr.kind {
+        ExprKind::Literal(v @ (Value::Nil | Value::Boolean(_) | Value::Integer(_) | Value::Number(_) | Value::String(_))) => Some(v),
+        _ => None,
+    }
+}
+
+fn fold_binary(left: &Expr, op: &Token, right: &Expr) -> Option<Value> {
+    let l = constant(left)?;
+    match op {
+        // The right operand is dropped when it would not be evaluated
+        Token::And if !is_truthy(l) => Some(l.clone()),
+        Token::Or if is_truthy(l) => Some(l.clone()),
+        Token::And | Token::Or => constant(right).cloned(),
+        _ => fold_operation(l, op, constant(right)?),
+    }
+}
+
+fn fold_operation(l: &Value, op: &Token, r: &Value) -> Option<Value> {
+    match (op, l, r) {
+        (Token::Plus, Value::Integer(a), Value::Integer(b)) => Some(Value::Integer(a.wrapping_add(*b))),
+        (Token::Minus, Value::Integer(a), Value::Integer(b)) => Some(Value::Integer(a.wrapping_sub(*b))),
+        (Token::Star, Value::Integer(a), Value::Integer(b)) => Some(Value::Integer(a.wrapping_mul(*b))),
+        (Token::DoubleForwardSlash, Value::Integer(a), Value::Integer(b)) if *b != 0 => {
+            let q = a.wrapping_div(*b);
+            Some(Value::Integer(if a.wrapping_rem(*b) != 0 && (a ^ b) < 0 { q - 1 } else { q }))
+        },
+        (Token::Percent, Value::Integer(a), Value::Integer(b)) if *b != 0 => {
+            let r = a.wrapping_rem(*b);
+            Some(Value::Integer(if r != 0 && (r ^ b) < 0 { r + b } else { r }))
+        },
+        // `n // 0` and `n % 0` raise errors on integers
+        (Token::DoubleForwardSlash | Token::Percent, Value::Integer(_), Value::Integer(_)) => None,
+        (Token::Plus | Token::Minus | Token::Star | Token::ForwardSlash | Token::Caret | Token::DoubleForwardSlash | Token::Percent, _, _) => {
+            let (a, b) = (to_float(l)?, to_float(r)?);
+            let n = match op {
+                Token::Plus => a + b,
+                Token::Minus => a - b,
+                Token::Star => a * b,
+                Token::ForwardSlash => a / b,
+                Token::Caret => a.powf(b),
+                Token::DoubleForwardSlash => (a / b).floor(),
+                _ => {
+                    let m = a % b;
+                    if (m > 0.0 && b < 0.0) || (m < 0.0 && b > 0.0) { m + b } else { m }
+                },
+            };
+            Some(Value::Number(OrderedFloat(n)))
+        },
+        (Token::Equals, _, _) => Some(Value::Boolean(raw_equals(l, r))),
+        (Token::NotEquals, _, _) => Some(Value::Boolean(!raw_equals(l, r))),
+        (Token::LessThan | Token::LessThanOrEqual | Token::GreaterThan | Token::GreaterThanOrEqual, _, _) => {
+            let ordering = compare_values(l, r)?;
+            // NaN is neither smaller, equal nor greater than anything
+            Some(Value::Boolean(ordering.is_some_and(|o| match op {
+                Token::LessThan => o == Ordering::Less,
+                Token::LessThanOrEqual => o != Ordering::Greater,
+                Token::GreaterThan => o == Ordering::Greater,
+                _ => o != Ordering::Less,
+            })))
+        },
+        // Floats are left alone, their conversion to text belongs to the interpreter
+        (Token::Concatenation, _, _) => {
+            let (a, b) = (concat_bytes(l)?, concat_bytes(r)?);
+            Some(Value::String([a, b].concat().into()))
+        },
+        (Token::Ampersand | Token::Pipe | Token::Tilde | Token::ShiftLeft | Token::ShiftRight, _, _) => {
+            let (a, b) = (to_integer(l)?, to_integer(r)?);
+            Some(Value::Integer(match op {
+                Token::Ampersand => a & b,
+                Token::Pipe => a | b,
+                Token::Tilde => a ^ b,
+                Token::ShiftLeft => shift_left(a, b),
+                _ => shift_left(a, b.wrapping_neg()),
+            }))
+        },
+        _ => None,
+    }
+}
+
+fn fold_unary(op: &Token, v: &Value) -> Option<Value> {
+    match (op, v) {
+        (Token::Minus, Value::Integer(i)) => Some(Value::Integer(i.wrapping_neg())),
+        (Token::Minus, Value::Number(n)) => Some(Value::Number(-*n)),
+        (Token::Not, _) => Some(Value::Boolean(!is_truthy(v))),
+        (Token::Pound, Value::String(s)) => Some(Value::Integer(s.len() as i64)),
+        (Token::Tilde, _) => to_integer(v).map(|i| Value::Integer(!i)),
+        _ => None,
+    }
+}
+
+fn concat_bytes(v: &Value) -> Option<Vec<u8>> {
+    match v {
+        Value::String(s) => Some(s.as_bytes().to_vec()),
+        Value::Integer(i) => Some(i.to_string().into_bytes()),
+        _ => None,
+    }
+}
@@ -1,105 +1,252 @@
-use std::collections::VecDeque;
+use std::fmt::Display;
+use std::ops::Deref;
 
-use ordered_float::OrderedFloat;
+use crate::{Token, Expr, Stmt, function::Function, values::Value, expr::{ExprKind, Field}, stmt::{StmtKind, LocalAttrib}, span::Span, tokens::SpannedToken, labels::check_labels, resolver::VarKind};
 
-use crate::{Token, Expr, Stmt, function::Function, values::Value};
+// Unary operators bind tighter than every binary operator except `^`
+const UNARY_PRIORITY: u8 = 12;
+
+// Nesting limit for expressions and blocks, deeper input is rejected instead of overflowing the stack
+const MAX_DEPTH: usize = 200;
+
+fn is_unary_operator(token: &Token) -> bool {
+    matches!(token, Token::Minus | Token::Not | Token::Pound | Token::Tilde)
+}
+
+// Left and right binding power of each binary operator, as in Lua 5.4's lparser.c.
+// A right priority lower than the left one makes the operator right associative (`..` and `^`)
+fn binary_priority(token: &Token) -> Option<(u8, u8)> {
+    let priority = match token {
+        Token::Or => (1, 1),
+        Token::And => (2, 2),
+        Token::LessThan | Token::GreaterThan | Token::LessThanOrEqual | Token::GreaterThanOrEqual | Token::NotEquals | Token::Equals => (3, 3),
+        Token::Pipe => (4, 4),
+        Token::Tilde => (5, 5),
+        Token::Ampersand => (6, 6),
+        Token::ShiftLeft | Token::ShiftRight => (7, 7),
+        Token::Concatenation => (9, 8),
+        Token::Plus | Token::Minus => (10, 10),
+        Token::Star | Token::ForwardSlash | Token::DoubleForwardSlash | Token::Percent => (11, 11),
+        Token::Caret => (14, 13),
+        _ => return None,
+    };
+    Some(priority)
+}
+
+// Tokens that end a block, see `statements`
+fn is_block_follow(token: &Option<Token>) -> bool {
+    matches!(token, None | Some(Token::End | Token::Else | Token::Elseif | Token::Until))
+}
+
+fn is_statement_start(token: &Token) -> bool {
+    matches!(token, Token::Local | Token::Function | Token::Semicolon | Token::If | Token::While | Token::For | Token::Do |
+        Token::Repeat | Token::Return | Token::Break | Token::Goto | Token::DoubleColon)
+}
+
+// How many blocks a token opens (or closes, when negative)
+fn block_delta(token: &Token) -> isize {
+    match token {
+        Token::Do | Token::If | Token::Function | Token::Repeat => 1,
+        Token::End | Token::Until => -1,
+        _ => 0,
+    }
+}
+
+// An error found while parsing. The details are boxed so that `Result<_, ParseError>` stays small
+#[derive(Clone)]
+pub struct ParseError(Box<ParseErrorDetails>);
+
+// `found` is the token the parser stopped at, `expected` lists the tokens that would have been accepted there,
+// if the parser knows them
+#[derive(Clone)]
+pub struct ParseErrorDetails {
+    pub span: Span,
+    pub found: Option<Token>,
+    pub at_eof: bool,
+    pub expected: Vec<Token>,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new(span: Span, found: Option<Token>, expected: Vec<Token>, message: String) -> Self {
+        let at_eof = found.is_none();
+        Self(Box::new(ParseErrorDetails { span, found, at_eof, expected, message }))
+    }
+
+    // An error about the meaning of the code rather than its syntax, like assigning to a `<const>` local
+    pub fn semantic(span: Span, message: String) -> Self {
+        Self(Box::new(ParseErrorDetails { span, found: None, at_eof: false, expected: vec![], message }))
+    }
+}
+
+impl Deref for ParseError {
+    type Target = ParseErrorDetails;
+
+    fn deref(&self) -> &ParseErrorDetails {
+        &self.0
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)?;
+        if let Some(found) = &self.found {
+            write!(f, " near '{found}'")
+        } else if self.at_eof {
+            write!(f, " near <eof>")
+        } else {
+            Ok(())
+        }
+    }
+}
 
 pub struct Parser {
-    tokens: Vec<Token>,
+    tokens: Vec<SpannedToken>,
     current: usize,
+    // Locals declared in each enclosing block, innermost last, used to reject assignments to `<const>` locals
+    scopes: Vec<Vec<(String, Option<LocalAttrib>)>>,
+    depth: usize,
+    // Errors of statements that were skipped, see `statements`
+    errors: Vec<ParseError>,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        return Self { tokens, current: 0 }
+    pub fn new(tokens: Vec<SpannedToken>) -> Self {
+        return Self { tokens, current: 0, scopes: vec![vec![]], depth: 0, errors: vec![] }
     }
 
-    fn primary(&mut self) -> Result<Expr, String> {
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
         if let Some(Token::Literal(v)) = self.current_token() {
             self.advance();
-            return Ok(Expr::Literal(v.clone()));
+            return Ok(Expr::new(ExprKind::Literal(v.clone()), start));
         } else if self.check_token_type(Token::LeftParens) {
-            let expr_res = self.expr_list();
-            if let Ok(expr) = expr_res {
-                let expr = Expr::Grouping(Box::new(expr));
-                if !self.check_token_type(Token::RightParens) {
-                    return Err("Missing right parens".into());
-                }
-                return Ok(expr);
-            }
-            return expr_res;
+            let expr = self.expression()?;
+            self.expect_match(Token::RightParens, Token::LeftParens, start.line)?;
+            return Ok(Expr::new(ExprKind::Grouping(Box::new(expr)), self.span_from(start)));
         } else if let Some(Token::Identifier(s)) = self.current_token() {
             self.advance();
-            return Ok(Expr::Var(s));
+            return Ok(Expr::new(ExprKind::Var(s, VarKind::Global), start));
         } else if Some(Token::Function) == self.current_token() {
             return self.function_def();
         } else if Some(Token::LeftCurlyBrace) == self.current_token() {
             return self.table();
         } else if self.check_token_type(Token::Varargs) {
-            self.advance();
-            return Ok(Expr::Varargs);
+            return Ok(Expr::new(ExprKind::Varargs, start));
         }
-        return Err("Unknown token".into());
+        return Err(self.error("unexpected symbol"));
     }
 
-    fn function_def(&mut self) -> Result<Expr, String> {
+    // An anonymous function, `function (params) body end`
+    fn function_def(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
         self.advance();
-        let mut f_name = None;
-        if let Some(Token::Identifier(s)) = self.current_token() {
-            f_name = Some(s);
-            self.advance();
+        return self.function_body(start, None, false);
+    }
+
+    // Parameters and body of a function. Methods get an implicit `self` as their first parameter
+    fn function_body(&mut self, start: Span, f_name: Option<String>, is_method: bool) -> Result<Expr, ParseError> {
+        self.expect(Token::LeftParens)?;
+        let mut params = vec![];
+        if is_method {
+            params.push(Expr::new(ExprKind::Var("self".into(), VarKind::Global), start));
         }
-        assert!(self.check_token_type(Token::LeftParens), "Function definition needs an opening parentheses");
-        let params ;
-        
         if self.current_token() != Some(Token::RightParens) {
-            params = self.expr_list()?;
-        } else {
-            params = Expr::Exprlist(vec![]);
+            loop {
+                let param_span = self.current_span();
+                if self.check_token_type(Token::Varargs) {
+                    params.push(Expr::new(ExprKind::Varargs, param_span));
+                    break;
+                }
+                let (name, name_span) = self.expect_name()?;
+                params.push(Expr::new(ExprKind::Var(name, VarKind::Global), name_span));
+                if !self.check_token_type(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.expect(Token::RightParens)?;
+        let errors_before = self.errors.len();
+        self.open_scope();
+        for param in params.iter() {
+            if let ExprKind::Var(name, _) = &param.kind {
+                self.declare_local(name, None);
+            }
         }
+        let body_start = self.current_span();
+        let body = self.do_block(Token::Function, start.line)?;
+        self.close_scope();
+        // Skipped statements may have held the labels, checking would only report follow-up errors
+        if self.errors.len() == errors_before {
+            check_labels(&body)?;
+        }
+        let body = Box::new(Stmt::new(StmtKind::Block(body), self.span_from(body_start)));
+        return Ok(Expr::new(ExprKind::Literal(Value::FunctionDef(Function::new(body, params, f_name))), self.span_from(start)));
+    }
 
-        assert!(self.check_token_type(Token::RightParens), "Function definition needs a closing parentheses");
-        if let Expr::Exprlist(params) = params {
-            let body = Box::new(Stmt::Block(self.do_block()?));
-            return Ok(Expr::Literal(Value::FunctionDef(Function::new(body, params, f_name, VecDeque::new()))));
-        } else {
-            return Err("Invalid parameter in function definition".into());
+    // The name in `function a.b.c:m() ... end`. Returns the assignment target, its printable name, and whether it is a method
+    fn function_name(&mut self) -> Result<(Expr, String, bool), ParseError> {
+        let start = self.current_span();
+        let (name, _) = self.expect_name()?;
+        let mut target = Expr::new(ExprKind::Var(name.clone(), VarKind::Global), start);
+        let mut full_name = name;
+        let mut is_method = false;
+        while let Some(separator @ (Token::Period | Token::Colon)) = self.current_token() {
+            self.advance();
+            let (field, field_span) = self.expect_name()?;
+            full_name.push_str(&format!("{separator}{field}"));
+            let field = Expr::new(ExprKind::Literal(Value::String(field.into())), field_span);
+            target = Expr::new(ExprKind::Accessor(Box::new(target), Box::new(field)), self.span_from(start));
+            if separator == Token::Colon {
+                is_method = true;
+                break;
+            }
         }
+        return Ok((target, full_name, is_method));
     }
 
-    fn accessor(&mut self) -> Result<Expr, String> {
+    // Arguments of a call: `(exprs)`, a single string literal or a single table constructor
+    fn call_args(&mut self) -> Result<Option<Vec<Expr>>, ParseError> {
+        let start = self.current_span();
+        if self.check_token_type(Token::LeftParens) {
+            if self.check_token_type(Token::RightParens) {
+                return Ok(Some(vec![]));
+            }
+            let args = self.expr_list()?;
+            self.expect_match(Token::RightParens, Token::LeftParens, start.line)?;
+            if let ExprKind::Exprlist(args) = args.kind {
+                return Ok(Some(args));
+            }
+            return Ok(Some(vec![args]));
+        } else if let Some(Token::Literal(Value::String(s))) = self.current_token() {
+            self.advance();
+            return Ok(Some(vec![Expr::new(ExprKind::Literal(Value::String(s)), start)]));
+        } else if self.current_token() == Some(Token::LeftCurlyBrace) {
+            return Ok(Some(vec![self.table()?]));
+        }
+        return Ok(None);
+    }
+
+    fn accessor(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
         let mut left = self.primary()?;
         loop {
             if self.check_token_type(Token::Period) {
-                let field = self.primary()?;
-                if let Expr::Var(name) = field {
-                    left = Expr::Accessor(Box::new(left), Box::new(Expr::Literal(Value::String(name))));
-                }
+                let (name, name_span) = self.expect_name()?;
+                let field = Expr::new(ExprKind::Literal(Value::String(name.into())), name_span);
+                left = Expr::new(ExprKind::Accessor(Box::new(left), Box::new(field)), self.span_from(start));
             } else if self.check_token_type(Token::LeftSquareBracket) {
                 let right = self.expression()?;
-                left = Expr::Accessor(Box::new(left), Box::new(right));
-                if !self.check_token_type(Token::RightSquareBracket) {
-                    return Err("Missing right square bracket".into());
-                }
-            } else if self.check_token_type(Token::LeftParens) {
-                if !self.check_token_type(Token::RightParens) {
-                    let args = self.expr_list()?;
-                    if !self.check_token_type(Token::RightParens) {
-                        return Err("Function call is missing right parens".into());
-                    }
-                    if let Expr::Exprlist(args) = args {
-                        left = Expr::FunctionCall(Box::new(left), args);
-                    }
-                } else {
-                    left = Expr::FunctionCall(Box::new(left), vec![]);
-                }
-            } else if let Some(Token::Literal(v)) = self.current_token() {
-                self.advance();
-                if let Value::String(_) = v {
-                    left = Expr::FunctionCall(Box::new(left), vec![Expr::Literal(v)]);
-                }
-            } else if self.check_token_type(Token::LeftCurlyBrace) {
-                left = Expr::FunctionCall(Box::new(left), vec![self.field_list()?]);
+                self.expect(Token::RightSquareBracket)?;
+                left = Expr::new(ExprKind::Accessor(Box::new(left), Box::new(right)), self.span_from(start));
+            } else if self.check_token_type(Token::Colon) {
+                let (name, _) = self.expect_name()?;
+                let Some(args) = self.call_args()? else {
+                    return Err(self.error("function arguments expected"));
+                };
+                left = Expr::new(ExprKind::MethodCall(Box::new(left), name, args), self.span_from(start));
+            } else if let Some(args) = self.call_args()? {
+                left = Expr::new(ExprKind::FunctionCall(Box::new(left), args), self.span_from(start));
             } else {
                 break;
             }
@@ -107,307 +254,525 @@ impl Parser {
         return Ok(left);
     }
 
+    // Parses an expression whose binary operators all bind tighter than `limit`, see `binary_priority`
+    fn sub_expression(&mut self, limit: u8) -> Result<Expr, ParseError> {
+        self.enter_level()?;
+        let res = self.sub_expression_inner(limit);
+        self.depth -= 1;
+        res
+    }
 
-    fn unary(&mut self) -> Result<Expr, String> {
-        if self.check_token_type(Token::Minus) || self.check_token_type(Token::Not) || self.check_token_type(Token::Pound) {
-            let operator = self.previous_token();
-            if let Ok(right) = self.unary() {
-                return Ok(Expr::Unary(Box::new(right), operator));
-            } else {
-                return Err("Unsupported unary operation".into())
-            }
-        }
-        return self.accessor();
-    }
-
-    fn factor(&mut self) -> Result<Expr, String> {
-        let unary = self.unary();
-        if let Ok(mut expr) = unary {
-            while self.check_token_type(Token::Star) ||
-            self.check_token_type(Token::ForwardSlash) ||
-            self.check_token_type(Token::Percent) {
-                let operator = self.previous_token();
-                let right = self.unary();
-                if let Ok(right) = right {
-                    expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
-                } else {
-                    return right;
-                }
-            }
-    
-            return Ok(expr);
+    fn sub_expression_inner(&mut self, limit: u8) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        let mut left = if let Some(operator) = self.current_token().filter(is_unary_operator) {
+            self.advance();
+            let operand = self.sub_expression(UNARY_PRIORITY)?;
+            Expr::new(ExprKind::Unary(Box::new(operand), operator), self.span_from(start))
         } else {
-            return unary;
+            self.accessor()?
+        };
+        while let Some(operator) = self.current_token() {
+            let Some((left_priority, right_priority)) = binary_priority(&operator) else { break };
+            if left_priority <= limit {
+                break;
+            }
+            self.advance();
+            let right = self.sub_expression(right_priority)?;
+            left = Expr::new(ExprKind::Binary(Box::new(left), operator, Box::new(right)), self.span_from(start));
         }
+        return Ok(left);
     }
 
-    fn term(&mut self) -> Result<Expr, String> {
-        let mut expr = self.factor()?;
-
-        while self.check_token_type(Token::Plus) ||
-        self.check_token_type(Token::Minus) {
-            let operator = self.previous_token();
-            let right = self.factor()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
-        }
-
-        return Ok(expr);
+    fn is_field_seperator(&mut self) -> bool {
+        self.check_token_type(Token::Comma) || self.check_token_type(Token::Semicolon)
     }
 
-    fn concat(&mut self) -> Result<Expr, String> {
-        let mut expr = self.term()?;
-        while self.check_token_type(Token::Concatenation) {
-            expr = Expr::Binary(Box::new(expr), Token::Concatenation, Box::new(self.term()?));
+    fn field_list(&mut self) -> Result<Expr, ParseError> {
+        // The opening brace has already been consumed
+        let start = self.previous_span();
+        let mut fields: Vec<Field> = vec![];
+        while self.current_token() != Some(Token::RightCurlyBrace) {
+            if self.check_token_type(Token::LeftSquareBracket) {
+                let key = self.expression()?;
+                self.expect(Token::RightSquareBracket)?;
+                self.expect(Token::Assign)?;
+                let value = self.expression()?;
+                fields.push(Field::Keyed(Box::new(key), Box::new(value)));
+            } else if let (Some(Token::Identifier(name)), Some(Token::Assign)) = (self.current_token(), self.peek_token()) {
+                let key = Expr::new(ExprKind::Literal(Value::String(name.into())), self.current_span());
+                self.advance();
+                self.advance();
+                let value = self.expression()?;
+                fields.push(Field::Keyed(Box::new(key), Box::new(value)));
+            } else {
+                fields.push(Field::Positional(Box::new(self.expression()?)));
+            }
+            if !self.is_field_seperator() {
+                break;
+            }
         }
-        return Ok(expr);
+        self.expect_match(Token::RightCurlyBrace, Token::LeftCurlyBrace, start.line)?;
+        Ok(Expr::new(ExprKind::FieldList(fields), self.span_from(start)))
     }
 
-    fn comparison(&mut self) -> Result<Expr, String> {
-        let mut expr = self.concat()?;
-        while self.check_token_type(Token::LessThan) ||
-            self.check_token_type(Token::LessThanOrEqual) ||
-            self.check_token_type(Token::Equals) ||
-            self.check_token_type(Token::NotEquals) ||
-            self.check_token_type(Token::GreaterThanOrEqual) ||
-            self.check_token_type(Token::GreaterThan) {
-                let operator = self.previous_token();
-                let right = self.concat()?;
-                expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
-        }
-
-            return Ok(expr);
+    fn table(&mut self) -> Result<Expr, ParseError> {
+        self.advance();
+        return self.field_list();
     }
 
-    fn equality(&mut self) -> Result<Expr, String> {
-        let mut expr = self.comparison()?;
+    fn expression(&mut self) -> Result<Expr, ParseError> {
+        return self.sub_expression(0);
+    }
 
-        while self.check_token_type(Token::Equals) ||
-        self.check_token_type(Token::NotEquals) {
-            let operator = self.previous_token();
-            let right = self.term()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+    fn expr_list(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        let mut expr_vec = vec![self.expression()?];
+        while self.check_token_type(Token::Comma) {
+            expr_vec.push(self.expression()?);
         }
-
-        return Ok(expr);
+        return Ok(Expr::new(ExprKind::Exprlist(expr_vec), self.span_from(start)));
     }
 
-    fn is_field_seperator(&mut self) -> bool {
-        self.check_token_type(Token::Comma) || self.check_token_type(Token::Semicolon)
+    fn numeric_for_loop(&mut self, start: Span) -> Result<Stmt, ParseError> {
+        let (name, name_span) = self.expect_name()?;
+        let control_var = Expr::new(ExprKind::Exprlist(vec![Expr::new(ExprKind::Var(name.clone(), VarKind::Global), name_span)]), name_span);
+        self.expect(Token::Assign)?;
+        let control_value = self.expression()?;
+        self.expect(Token::Comma)?;
+        let limit = self.expression()?;
+        let step = if self.check_token_type(Token::Comma) {
+            self.expression()?
+        } else {
+            Expr::new(ExprKind::Literal(Value::Integer(1)), limit.span)
+        };
+        self.expect(Token::Do)?;
+        self.open_scope();
+        self.declare_local(&name, None);
+        let body = self.do_block(Token::For, start.line)?;
+        self.close_scope();
+        return Ok(Stmt::new(StmtKind::NumericForLoop(control_var, control_value, Box::new(limit), Box::new(step), body), self.span_from(start)));
     }
 
-    fn field_list(&mut self) -> Result<Expr, String> {
-        let mut fields: Vec<(Box<Expr>, Box<Expr>)> = vec![];
-        let mut field_counter = 1;
-        while !self.check_token_type(Token::RightCurlyBrace) {
-            if self.check_token_type(Token::LeftSquareBracket) {
-                let key = self.expression()?;
-                assert!(self.check_token_type(Token::RightSquareBracket), "expect closing bracket when declaring fields in a table");
-                assert!(self.check_token_type(Token::Assign), "field needs to be assigned to");
-                let value = self.expression()?;
-                fields.push((Box::new(key), Box::new(value)));
-                assert!(self.is_field_seperator() || self.current_token() == Some(Token::RightCurlyBrace), "Fields need to be properly separated");
-            } else {
-                let expr = self.expression()?;
-                if self.is_field_seperator() || self.current_token() == Some(Token::RightCurlyBrace) {
-                    fields.push((Box::new(Expr::Literal(Value::Number(OrderedFloat(field_counter as f32)))), Box::new(expr)));
-                } else if self.check_token_type(Token::Assign) {
-                    if let Expr::Var(s) = expr {
-                        let value = self.expression()?;
-                        fields.push((Box::new(Expr::Literal(Value::String(s))), Box::new(value)));
-
-                    }
+    // `for namelist in explist do block end`
+    fn generic_for_loop(&mut self, start: Span) -> Result<Stmt, ParseError> {
+        let names_start = self.current_span();
+        let mut names = vec![];
+        loop {
+            let (name, name_span) = self.expect_name()?;
+            names.push(Expr::new(ExprKind::Var(name, VarKind::Global), name_span));
+            if !self.check_token_type(Token::Comma) {
+                break;
+            }
+        }
+        let names_span = self.span_from(names_start);
+        // A single name may still start a numeric loop, a name list can only be followed by `in`
+        if names.len() > 1 {
+            self.expect(Token::In)?;
+        } else if !self.check_token_type(Token::In) {
+            return Err(self.error_expected(vec![Token::Assign, Token::In], "'=' or 'in' expected".into()));
+        }
+        let names = Expr::new(ExprKind::Exprlist(names), names_span);
+        let exprs = self.expr_list()?;
+        self.expect(Token::Do)?;
+        self.open_scope();
+        if let ExprKind::Exprlist(vars) = &names.kind {
+            for var in vars.iter() {
+                if let ExprKind::Var(name, _) = &var.kind {
+                    self.declare_local(name, None);
                 }
             }
-            field_counter += 1;
         }
-        Ok(Expr::FieldList(fields))
+        let body = self.do_block(Token::For, start.line)?;
+        self.close_scope();
+        return Ok(Stmt::new(StmtKind::GenericForLoop(names, exprs, body), self.span_from(start)));
     }
 
-    fn table(&mut self) -> Result<Expr, String> {
-        if self.check_token_type(Token::LeftCurlyBrace) {
-            return self.field_list();
+    fn assignment(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.current_span();
+        if self.check_token_type(Token::Function) {
+            let (target, full_name, is_method) = self.function_name()?;
+            self.check_assignable(&target)?;
+            let func = self.function_body(start, Some(full_name), is_method)?;
+            let span = self.span_from(start);
+            let name = Expr::new(ExprKind::Exprlist(vec![target]), span);
+            let value = Expr::new(ExprKind::Exprlist(vec![func]), span);
+            return Ok(Stmt::new(StmtKind::Assignment(name, value), span));
+        }
+        let first = self.suffixed_expression()?;
+        if matches!(self.current_token(), Some(Token::Assign | Token::Comma)) {
+            let mut targets = vec![first];
+            loop {
+                if let Some(target) = targets.last() {
+                    self.check_target(target)?;
+                }
+                if !self.check_token_type(Token::Comma) {
+                    break;
+                }
+                targets.push(self.suffixed_expression()?);
+            }
+            self.expect(Token::Assign)?;
+            let left = Expr::new(ExprKind::Exprlist(targets), self.span_from(start));
+            let right = self.expr_list()?;
+            return Ok(Stmt::new(StmtKind::Assignment(left, right), self.span_from(start)));
+        }
+        // Anything that is not assigned to has to be a call
+        if !matches!(first.kind, ExprKind::FunctionCall(_, _) | ExprKind::MethodCall(_, _, _)) {
+            return Err(self.error("syntax error"));
         }
-        return self.equality();
+        let expr = Expr::new(ExprKind::Exprlist(vec![first]), self.span_from(start));
+        return Ok(Stmt::new(StmtKind::ExprStmt(expr), self.span_from(start)));
     }
 
-    fn and(&mut self) -> Result<Expr, String> {
-        let mut expr = self.equality()?;
-        while self.check_token_type(Token::And) {
-            expr = Expr::Binary(Box::new(expr), Token::And, Box::new(self.or()?));
+    // A name or a parenthesized expression followed by any number of field accesses, indexes and calls.
+    // Statements start with one of these, unlike expressions they cannot be literals
+    fn suffixed_expression(&mut self) -> Result<Expr, ParseError> {
+        if !matches!(self.current_token(), Some(Token::Identifier(_) | Token::LeftParens)) {
+            return Err(self.error("unexpected symbol"));
         }
-        return Ok(expr);
+        self.accessor()
     }
 
-    fn or(&mut self) -> Result<Expr, String> {
-        let mut expr = self.and()?;
-        while self.check_token_type(Token::Or) {
-            expr = Expr::Binary(Box::new(expr), Token::Or, Box::new(self.table()?));
+    // Only variables and table fields can be assigned to, `f() = 1` or `(a) = 1` cannot
+    fn check_target(&self, target: &Expr) -> Result<(), ParseError> {
+        match target.kind {
+            ExprKind::Var(..) => self.check_assignable(target),
+            ExprKind::Accessor(_, _) => Ok(()),
+            _ => Err(self.error("syntax error")),
         }
-        return Ok(expr);
     }
 
-
-    fn expression(&mut self) -> Result<Expr, String> {
-        return self.or();
+    // A block closed by `end`. `opener` and `line` tell which construct the `end` belongs to, for error messages
+    fn do_block(&mut self, opener: Token, line: usize) -> Result<Vec<Stmt>, ParseError> {
+        self.open_scope();
+        let res = self.statements()?;
+        self.close_scope();
+        self.expect_match(Token::End, opener, line)?;
+        return Ok(res);
     }
 
-    fn expr_list(&mut self) -> Result<Expr, String> {
-        let mut expr_vec = vec![self.expression()?];
-        let mut found_varargs = false;
-        while self.check_token_type(Token::Comma) {
-            if found_varargs {
-                panic!("Varargs (...) must only appear once at the end of the parameter list");
+    // Statements up to the end of the enclosing block. A return statement has to be the last one.
+    // A statement with an error is recorded in `errors` and left out, parsing resumes at the next statement boundary
+    fn statements(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        let mut res = vec![];
+        while !is_block_follow(&self.current_token()) {
+            let is_return = self.current_token() == Some(Token::Return);
+            let start = self.current;
+            let scopes = self.scopes.len();
+            match self.statement() {
+                Ok(stmt) => res.push(stmt),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.scopes.truncate(scopes);
+                    self.synchronize(start);
+                    continue;
+                }
             }
-            if self.check_token_type(Token::Varargs) {
-                expr_vec.push(Expr::Varargs);
-                found_varargs = true;
-            } else {
-                expr_vec.push(self.expression()?);                
+            if is_return {
+                break;
             }
         }
-        return Ok(Expr::Exprlist(expr_vec));
+        return Ok(res);
     }
 
-    fn numeric_for_loop(&mut self) -> Result<Stmt, String> {
-        let control_var = Expr::Exprlist(vec![self.primary()?]);
-        assert!(self.check_token_type(Token::Assign), "Missing \"=\" when assigning to control var");
-        let control_value = self.expression()?;
-        assert!(self.check_token_type(Token::Comma), "Numeric for loop needs proper separation of fields");
-        let limit = self.expression()?;
-        let step = if self.check_token_type(Token::Comma) {
-            self.expression()?
-        } else {
-            Expr::Literal(Value::Number(1.0f32.into()))
-        };
-        assert!(self.check_token_type(Token::Do), "For loop missing \"do\" keyword");
-        let body = self.do_block()?;
-        return Ok(Stmt::NumericForLoop(control_var, control_value, limit, step, body));
-    }
-
-    fn assignment(&mut self) -> Result<Stmt, String> {
-        if self.current_token() == Some(Token::Function) {
-            let func = self.function_def()?;
-            if let Expr::Literal(Value::FunctionDef(fd)) = func {
-                if let Some(id_str) = fd.get_name() {
-                    return Ok(Stmt::Assignment(Expr::Exprlist(vec![Expr::Var(id_str)]), Expr::Exprlist(vec![Expr::Literal(Value::FunctionDef(fd))])));
-                } else {
-                    return Err("Cannot assign to function without name".into());
-                }
-            } else {
-                return Err("Invalid function definition".into());
+    // Skips the rest of a statement that failed to parse. Blocks it opened are skipped up to their `end`,
+    // otherwise this stops at the next token a statement can start with, or at the end of the enclosing block.
+    // Names and parentheses also start statements, but only the ones at the start of a line are taken as such
+    fn synchronize(&mut self, start: usize) {
+        if self.current == start {
+            self.advance();
+        }
+        let mut depth: isize = self.tokens[start..self.current].iter().map(|t| block_delta(&t.token)).sum();
+        while let Some(token) = self.current_token() {
+            let starts_line = self.current_span().line > self.previous_span().end_line;
+            let name_led = starts_line && matches!(token, Token::Identifier(_) | Token::LeftParens);
+            if depth <= 0 && (is_block_follow(&Some(token.clone())) || is_statement_start(&token) || name_led) {
+                return;
+            }
+            depth += block_delta(&token);
+            self.advance();
+            if depth == 0 && matches!(token, Token::End | Token::Until) {
+                return;
             }
         }
-        let expr = self.expr_list()?;
-        if self.check_token_type(Token::Assign) {
-            let right = self.expr_list()?;
-            return Ok(Stmt::Assignment(expr, right));
+    }
+
+    fn open_scope(&mut self) {
+        self.scopes.push(vec![]);
+    }
+
+    fn close_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    fn declare_local(&mut self, name: &str, attrib: Option<LocalAttrib>) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push((name.into(), attrib));
         }
-        return Ok(Stmt::ExprStmt(expr));
     }
 
-    fn do_block(&mut self) -> Result<Vec<Stmt>, String> {
-        let mut res = vec![];
-        while !self.check_token_type(Token::End) && self.current < self.tokens.len() {
-            res.push(self.statement()?);
+    // `<close>` locals are read-only too. Names that are not locals are globals, which can always be assigned
+    fn check_assignable(&self, target: &Expr) -> Result<(), ParseError> {
+        if let ExprKind::Var(name, _) = &target.kind {
+            let declaration = self.scopes.iter().rev().flat_map(|scope| scope.iter().rev()).find(|(n, _)| n == name);
+            if let Some((_, Some(_))) = declaration {
+                return Err(ParseError::semantic(target.span, format!("attempt to assign to const variable '{}'", name)));
+            }
         }
-        assert!(self.previous_token() == Token::End, "Missing \"End\" keyword");
-        return Ok(res);
+        Ok(())
     }
 
-    fn local_assignment(&mut self) -> Result<Stmt, String> {
-        let assign_stmt = self.assignment()?;
-        match assign_stmt {
-            Stmt::Assignment(vars, vals) => {
-                return Ok(Stmt::LocalAssignment(vars, vals));
-            },
-            Stmt::ExprStmt(vars) => {
-                return Ok(Stmt::LocalAssignment(vars, Expr::Exprlist(vec![])));
-            },
-            _ => Err("Invalid local assignment".into())
+    fn local_assignment(&mut self, start: Span) -> Result<Stmt, ParseError> {
+        if self.check_token_type(Token::Function) {
+            let (name, name_span) = self.expect_name()?;
+            self.declare_local(&name, None);
+            let func = self.function_body(start, Some(name.clone()), false)?;
+            let name = Expr::new(ExprKind::Var(name, VarKind::Global), name_span);
+            return Ok(Stmt::new(StmtKind::LocalFunction(name, func), self.span_from(start)));
         }
+        let names_start = self.current_span();
+        let mut names = vec![];
+        let mut attribs = vec![];
+        loop {
+            let (name, name_span) = self.expect_name()?;
+            names.push(Expr::new(ExprKind::Var(name, VarKind::Global), name_span));
+            attribs.push(self.local_attrib()?);
+            if !self.check_token_type(Token::Comma) {
+                break;
+            }
+        }
+        let names_span = self.span_from(names_start);
+        if attribs.iter().filter(|a| **a == Some(LocalAttrib::Close)).count() > 1 {
+            return Err(ParseError::semantic(names_start, "multiple to-be-closed variables in local list".into()));
+        }
+        let vals = if self.check_token_type(Token::Assign) {
+            self.expr_list()?
+        } else {
+            Expr::new(ExprKind::Exprlist(vec![]), self.previous_span())
+        };
+        // Declared only now, the values cannot refer to the new locals
+        for (name, attrib) in names.iter().zip(attribs.iter()) {
+            if let ExprKind::Var(name, _) = &name.kind {
+                self.declare_local(name, *attrib);
+            }
+        }
+        let names = Expr::new(ExprKind::Exprlist(names), names_span);
+        return Ok(Stmt::new(StmtKind::LocalAssignment(names, vals, attribs), self.span_from(start)));
     }
 
-    fn repeat_body(&mut self) -> Result<Stmt, String> {
-        let mut body = vec![];
-        while !self.check_token_type(Token::Until) {
-            body.push(self.statement()?);
+    // An optional `<const>` or `<close>` after a local's name
+    fn local_attrib(&mut self) -> Result<Option<LocalAttrib>, ParseError> {
+        if !self.check_token_type(Token::LessThan) {
+            return Ok(None);
         }
-        Ok(Stmt::Block(body))
+        let (name, name_span) = self.expect_name()?;
+        let attrib = match name.as_str() {
+            "const" => LocalAttrib::Const,
+            "close" => LocalAttrib::Close,
+            _ => return Err(ParseError::semantic(name_span, format!("unknown attribute '{}'", name))),
+        };
+        self.expect(Token::GreaterThan)?;
+        Ok(Some(attrib))
     }
 
-    fn if_statement(&mut self) -> Result<Stmt, String> {
+    fn if_statement(&mut self, start: Span) -> Result<Stmt, ParseError> {
         let cond = self.expression()?;
-        assert!(self.check_token_type(Token::Then), "If statement missing \"then\" keyword");
-        let mut stmts: Vec<Stmt> = vec![];
-        while !self.check_token_type(Token::Else) && !self.check_token_type(Token::Elseif) && !self.check_token_type(Token::End) && self.current < self.tokens.len() {
-            stmts.push(self.statement()?);
-        }
-        if self.previous_token() == Token::Else {
-            return Ok(Stmt::IfStmt(cond, Box::new(Stmt::Block(stmts)), Box::new(Stmt::Block(self.do_block()?))));
-        } else if self.previous_token() == Token::Elseif {
-            return Ok(Stmt::IfStmt(cond, Box::new(Stmt::Block(stmts)), Box::new(self.if_statement()?)));
+        self.expect(Token::Then)?;
+        let body_start = self.current_span();
+        self.open_scope();
+        let stmts = self.statements()?;
+        self.close_scope();
+        let body = Box::new(Stmt::new(StmtKind::Block(stmts), self.span_from(body_start)));
+        let else_start = self.current_span();
+        if self.check_token_type(Token::Else) {
+            let else_body = self.do_block(Token::If, start.line)?;
+            let else_body = Box::new(Stmt::new(StmtKind::Block(else_body), self.span_from(else_start)));
+            return Ok(Stmt::new(StmtKind::IfStmt(cond, body, else_body), self.span_from(start)));
+        } else if self.check_token_type(Token::Elseif) {
+            let else_body = Box::new(self.if_statement(else_start)?);
+            return Ok(Stmt::new(StmtKind::IfStmt(cond, body, else_body), self.span_from(start)));
         } else {
-            return Ok(Stmt::IfStmt(cond, Box::new(Stmt::Block(stmts)), Box::new(Stmt::Empty)));
+            self.expect_match(Token::End, Token::If, start.line)?;
+            let else_body = Box::new(Stmt::new(StmtKind::Empty, self.previous_span()));
+            return Ok(Stmt::new(StmtKind::IfStmt(cond, body, else_body), self.span_from(start)));
         }
+    }
 
+    fn statement(&mut self) -> Result<Stmt, ParseError> {
+        self.enter_level()?;
+        let res = self.statement_inner();
+        self.depth -= 1;
+        res
     }
 
-    fn statement(&mut self) -> Result<Stmt, String> {
+    fn statement_inner(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.current_span();
         if self.check_token_type(Token::Semicolon) {
-            return Ok(Stmt::Empty);
+            return Ok(Stmt::new(StmtKind::Empty, start));
         } else if self.check_token_type(Token::Do) {
-            let res = self.do_block()?;
-            return Ok(Stmt::DoBlock(res));
+            let res = self.do_block(Token::Do, start.line)?;
+            return Ok(Stmt::new(StmtKind::DoBlock(res), self.span_from(start)));
         } else if self.check_token_type(Token::Local) {
-            return self.local_assignment();
+            return self.local_assignment(start);
         } else if self.check_token_type(Token::If) {
-            return self.if_statement();
+            return self.if_statement(start);
         } else if self.check_token_type(Token::While) {
             let cond = self.expression()?;
-            assert!(self.check_token_type(Token::Do), "while loop missing \"do\" keyword");
-            let body = self.do_block()?;
-            return Ok(Stmt::WhileLoop(cond, Box::new(Stmt::Block(body))));
+            self.expect(Token::Do)?;
+            let body_start = self.current_span();
+            let body = self.do_block(Token::While, start.line)?;
+            let body = Box::new(Stmt::new(StmtKind::Block(body), self.span_from(body_start)));
+            return Ok(Stmt::new(StmtKind::WhileLoop(cond, body), self.span_from(start)));
         } else if self.check_token_type(Token::Repeat) {
-            let body = self.repeat_body()?;
+            // The condition can see the locals of the body
+            self.open_scope();
+            let body_start = self.current_span();
+            let body = self.statements()?;
+            let body = Stmt::new(StmtKind::Block(body), self.span_from(body_start));
+            self.expect_match(Token::Until, Token::Repeat, start.line)?;
             let cond = self.expression()?;
-            return Ok(Stmt::RepeatUntilLoop(Box::new(body), cond));
+            self.close_scope();
+            return Ok(Stmt::new(StmtKind::RepeatUntilLoop(Box::new(body), cond), self.span_from(start)));
         } else if self.check_token_type(Token::Return) {
-            return Ok(Stmt::Return(self.expr_list()?));
+            let ret = if is_block_follow(&self.current_token()) || self.current_token() == Some(Token::Semicolon) {
+                Expr::new(ExprKind::Exprlist(vec![]), self.previous_span())
+            } else {
+                self.expr_list()?
+            };
+            self.check_token_type(Token::Semicolon);
+            return Ok(Stmt::new(StmtKind::Return(ret), self.span_from(start)));
         } else if self.check_token_type(Token::Break) {
-            return Ok(Stmt::Break);
+            return Ok(Stmt::new(StmtKind::Break, start));
+        } else if self.check_token_type(Token::Goto) {
+            let (label, _) = self.expect_name()?;
+            return Ok(Stmt::new(StmtKind::Goto(label), self.span_from(start)));
+        } else if self.check_token_type(Token::DoubleColon) {
+            let (label, _) = self.expect_name()?;
+            self.expect(Token::DoubleColon)?;
+            return Ok(Stmt::new(StmtKind::Label(label), self.span_from(start)));
         } else if self.check_token_type(Token::For) {
-            return self.numeric_for_loop();
+            if self.peek_token() == Some(Token::Assign) {
+                return self.numeric_for_loop(start);
+            }
+            return self.generic_for_loop(start);
         }
         return self.assignment();
     }
 
-
-    fn block(&mut self) -> Result<Vec<Stmt>, String> {
+    fn block(&mut self) -> Vec<Stmt> {
         let mut res = vec![];
-        while self.current < self.tokens.len() {
-            res.push(self.statement()?);
+        loop {
+            if let Ok(mut stmts) = self.statements() {
+                res.append(&mut stmts);
+            }
+            // A stray `end`, `else` or `until` at the top level, or anything after a return statement
+            if self.current_token().is_none() {
+                break;
+            }
+            self.errors.push(self.error("'<eof>' expected"));
+            self.synchronize(self.current);
+        }
+        res
+    }
+
+    // Parses the whole input, stopping at the first error
+    pub fn chunk(&mut self) -> Result<Stmt, ParseError> {
+        let (chunk, mut errors) = self.chunk_with_errors();
+        if errors.is_empty() {
+            return Ok(chunk);
         }
-        Ok(res)
+        Err(errors.swap_remove(0))
     }
 
-    pub fn chunk(&mut self) -> Result<Stmt, String> {
-        if self.tokens.len() == 0 {
-            return Err("No valid tokens".into());
+    // Parses the whole input, returning every error found along with the statements that parsed fine
+    pub fn chunk_with_errors(&mut self) -> (Stmt, Vec<ParseError>) {
+        let start = self.current_span();
+        let block = self.block();
+        if self.errors.is_empty() {
+            if let Err(err) = check_labels(&block) {
+                self.errors.push(err);
+            }
         }
+        let chunk = Stmt::new(StmtKind::Chunk(block), self.span_from(start));
+        return (chunk, std::mem::take(&mut self.errors));
+    }
+
+    // An error at the current token
+    fn error(&self, message: &str) -> ParseError {
+        self.error_expected(vec![], message.into())
+    }
 
-        return Ok(Stmt::Chunk(self.block()?));
+    fn error_expected(&self, expected: Vec<Token>, message: String) -> ParseError {
+        ParseError::new(self.current_span(), self.current_token(), expected, message)
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), ParseError> {
+        if self.check_token_type(token.clone()) {
+            return Ok(());
+        }
+        let message = format!("'{token}' expected");
+        Err(self.error_expected(vec![token], message))
+    }
+
+    // Like `expect`, but for the token closing `opener`. Points back at the opener when it is on another line
+    fn expect_match(&mut self, closer: Token, opener: Token, line: usize) -> Result<(), ParseError> {
+        if self.check_token_type(closer.clone()) {
+            return Ok(());
+        }
+        let message = if line == self.current_span().line {
+            format!("'{closer}' expected")
+        } else {
+            format!("'{closer}' expected (to close '{opener}' at line {line})")
+        };
+        Err(self.error_expected(vec![closer], message))
+    }
+
+    fn expect_name(&mut self) -> Result<(String, Span), ParseError> {
+        let span = self.current_span();
+        if let Some(Token::Identifier(name)) = self.current_token() {
+            self.advance();
+            return Ok((name, span));
+        }
+        Err(self.error_expected(vec![Token::Identifier(String::new())], "<name> expected".into()))
+    }
+
+    fn enter_level(&mut self) -> Result<(), ParseError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error(&format!("too many nested levels (limit is {MAX_DEPTH})")));
+        }
+        self.depth += 1;
+        Ok(())
     }
 
     fn current_token(&self) -> Option<Token> {
-        return self.tokens.get(self.current).cloned();
+        return self.tokens.get(self.current).map(|t| t.token.clone());
+    }
+
+    fn peek_token(&self) -> Option<Token> {
+        return self.tokens.get(self.current + 1).map(|t| t.token.clone());
     }
 
-    fn previous_token(&self) -> Token {
+    // Span of the current token. Past the end of input, this is the empty span right after the last token
+    fn current_span(&self) -> Span {
+        if let Some(t) = self.tokens.get(self.current) {
+            return t.span;
+        }
+        if let Some(last) = self.tokens.last() {
+            let s = last.span;
+            return Span::new(s.end, s.end, s.end_line, s.end_column, s.end_line, s.end_column);
+        }
+        return Span::default();
+    }
+
+    fn previous_span(&self) -> Span {
         if self.current == 0 {
-            panic!("Went back before the beginning");
+            return self.current_span();
         }
+        return self.tokens[self.current - 1].span;
+    }
 
-        return self.tokens[self.current - 1].clone();
+    // Span from `start` up to and including the last consumed token
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.previous_span())
     }
 
     fn advance(&mut self) {
@@ -424,4 +789,67 @@ impl Parser {
 
         res
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn parse(src: &str) -> Stmt {
+        let tokens = Lexer::new(src).tokenize().expect("source should lex");
+        Parser::new(tokens).chunk().ok().expect("source should parse")
+    }
+
+    #[test]
+    fn local_names_span() {
+        let chunk = parse("local x <const>, y = \"a\\n\", 2");
+        let StmtKind::Chunk(stmts) = &chunk.kind else { panic!("not a chunk") };
+        let StmtKind::LocalAssignment(names, values, _) = &stmts[0].kind else { panic!("not a local assignment") };
+        assert_eq!(names.span, Span::new(6, 18, 1, 7, 1, 19));
+        assert_eq!(values.span, Span::new(21, 29, 1, 22, 1, 30));
+    }
+
+    fn errors(src: &str) -> Vec<String> {
+        let tokens = Lexer::new(src).tokenize().expect("source should lex");
+        let (_, errors) = Parser::new(tokens).chunk_with_errors();
+        errors.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn recovers_at_name_led_statements() {
+        assert_eq!(errors("x=1\ny = = 2\nz = +\nprint(1)"), [
+            "2:5: unexpected symbol near '='",
+            "3:5: unexpected symbol near '+'",
+        ]);
+        assert_eq!(errors("a = 1 +\nb = ;\n(f)() g = *\nlocal = 2"), [
+            "2:3: unexpected symbol near '='",
+            "3:11: unexpected symbol near '*'",
+            "4:7: <name> expected near '='",
+        ]);
+    }
+
+    #[test]
+    fn recovers_at_keywords_and_blocks() {
+        assert_eq!(errors("if x then y = = 1 end\nwhile do end\nreturn return"), [
+            "1:15: unexpected symbol near '='",
+            "2:7: unexpected symbol near 'do'",
+            "3:8: unexpected symbol near 'return'",
+        ]);
+    }
+
+    #[test]
+    fn for_expects_in_after_name_list() {
+        assert_eq!(errors("for a do end"), ["1:7: '=' or 'in' expected near 'do'"]);
+        assert_eq!(errors("for a, b do end"), ["1:10: 'in' expected near 'do'"]);
+    }
+
+    #[test]
+    fn resumes_after_code_following_return() {
+        assert_eq!(errors("return 1 print(2)"), ["1:10: '<eof>' expected near 'print'"]);
+        assert_eq!(errors("return 1 end\nx = = 1"), [
+            "1:10: '<eof>' expected near 'end'",
+            "2:5: unexpected symbol near '='",
+        ]);
+    }
+}
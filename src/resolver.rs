@@ -0,0 +1,356 @@
+use crate::{Expr, Stmt, Value, expr::ExprKind, stmt::StmtKind, parser::ParseError};
+use crate::visitor::{MutVisitor, walk_stmt_mut, walk_expr_mut};
+
+// How a variable reference is reached from the function it appears in
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VarKind {
+    // Slot in the frame of the current function. Slots are reused once a local goes out of scope
+    Local(usize),
+    // Index into the capture list of the current function
+    Upvalue(usize),
+    Global,
+}
+
+// A variable a function captures from the function it is defined in
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Capture {
+    pub name: String,
+    // Where the enclosing function keeps it, either `Local` or `Upvalue`
+    pub source: VarKind,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct FunctionInfo {
+    pub captures: Vec<Capture>,
+    // Number of slots the frame needs
+    pub slots: usize,
+}
+
+// Resolves every variable of a parsed chunk to a local slot, an upvalue or a global, and reports the errors
+// the parser leaves for later: `break` outside of a loop and `...` outside of a vararg function.
+// The result is written into the tree: every `Var` gets its kind, declarations (parameters, local names and
+// loop variables) included, and every function literal its `FunctionInfo`. Returns the info of the main chunk.
+pub fn resolve(chunk: &mut Stmt) -> Result<FunctionInfo, Vec<ParseError>> {
+    let mut resolver = Resolver { functions: vec![], errors: vec![] };
+    // The main chunk is a vararg function
+    resolver.functions.push(FunctionState::new(true));
+    resolver.visit_stmt_mut(chunk);
+    let main = resolver.functions.pop().map(FunctionState::info).unwrap_or_default();
+    if resolver.errors.is_empty() {
+        Ok(main)
+    } else {
+        Err(resolver.errors)
+    }
+}
+
+struct FunctionState {
+    // Names and slots of the locals in scope, innermost block last
+    scopes: Vec<Vec<(String, usize)>>,
+    active: usize,
+    slots: usize,
+    captures: Vec<Capture>,
+    loop_depth: usize,
+    is_vararg: bool,
+}
+
+impl FunctionState {
+    fn new(is_vararg: bool) -> Self {
+        Self { scopes: vec![vec![]], active: 0, slots: 0, captures: vec![], loop_depth: 0, is_vararg }
+    }
+
+    fn find_local(&self, name: &str) -> Option<usize> {
+        self.scopes.iter().rev()
+            .find_map(|scope| scope.iter().rev().find(|(n, _)| n == name))
+            .map(|(_, slot)| *slot)
+    }
+
+    fn info(self) -> FunctionInfo {
+        FunctionInfo { captures: self.captures, slots: self.slots }
+    }
+}
+
+struct Resolver {
+    // Functions being resolved, the innermost last
+    functions: Vec<FunctionState>,
+    errors: Vec<ParseError>,
+}
+
+impl Resolver {
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().expect("resolver has no function")
+    }
+
+    fn open_scope(&mut self) {
+        self.current().scopes.push(vec![]);
+    }
+
+    fn close_scope(&mut self) {
+        let function = self.current();
+        if let Some(scope) = function.scopes.pop() {
+            function.active -= scope.len();
+        }
+    }
+
+    fn declare(&mut self, name: &str) -> usize {
+        let function = self.current();
+        let slot = function.active;
+        function.active += 1;
+        function.slots = function.slots.max(function.active);
+        if let Some(scope) = function.scopes.last_mut() {
+            scope.push((name.to_string(), slot));
+        }
+        slot
+    }
+
+    // Declares the names in a `Var` or a list of them
+    fn declare_expr(&mut self, expr: &mut Expr) {
+        match &mut expr.kind {
+            ExprKind::Var(name, kind) => *kind = VarKind::Local(self.declare(name)),
+            ExprKind::Exprlist(exprs) => exprs.iter_mut().for_each(|e| self.declare_expr(e)),
+            _ => {},
+        }
+    }
+
+    // Looks `name` up from the function at `level`, adding captures to every function between it and the
+    // one that declares the variable. None means the variable is global.
+    fn find(&mut self, level: usize, name: &str) -> Option<VarKind> {
+        let function = &self.functions[level];
+        if let Some(slot) = function.find_local(name) {
+            return Some(VarKind::Local(slot));
+        }
+        if let Some(index) = function.captures.iter().position(|c| c.name == name) {
+            return Some(VarKind::Upvalue(index));
+        }
+        if level == 0 {
+            return None;
+        }
+        let source = self.find(level - 1, name)?;
+        let captures = &mut self.functions[level].captures;
+        captures.push(Capture { name: name.to_string(), source });
+        Some(VarKind::Upvalue(captures.len() - 1))
+    }
+
+    fn block(&mut self, stmts: &mut [Stmt]) {
+        self.open_scope();
+        stmts.iter_mut().for_each(|s| self.visit_stmt_mut(s));
+        self.close_scope();
+    }
+
+    fn loop_body(&mut self, stmts: &mut [Stmt]) {
+        self.current().loop_depth += 1;
+        stmts.iter_mut().for_each(|s| self.visit_stmt_mut(s));
+        self.current().loop_depth -= 1;
+    }
+}
+
+impl MutVisitor for Resolver {
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        match &mut stmt.kind {
+            StmtKind::Block(stmts) | StmtKind::DoBlock(stmts) | StmtKind::Chunk(stmts) => self.block(stmts),
+            StmtKind::LocalAssignment(names, values, _) => {
+                self.visit_expr_mut(values);
+                self.declare_expr(names);
+            },
+            StmtKind::LocalFunction(name, function) => {
+                // Declared first so the function can call itself
+                self.declare_expr(name);
+                self.visit_expr_mut(function);
+            },
+            StmtKind::WhileLoop(cond, body) => {
+                self.visit_expr_mut(cond);
+                self.loop_body(std::slice::from_mut(body));
+            },
+            StmtKind::RepeatUntilLoop(body, cond) => {
+                // The condition can see the locals of the body
+                self.open_scope();
+                match &mut body.kind {
+                    StmtKind::Block(stmts) => self.loop_body(stmts),
+                    _ => self.loop_body(std::slice::from_mut(body)),
+                }
+                self.visit_expr_mut(cond);
+                self.close_scope();
+            },
+            StmtKind::NumericForLoop(var, start, limit, step, body) => {
+                self.visit_expr_mut(start);
+                self.visit_expr_mut(limit);
+                self.visit_expr_mut(step);
+                self.open_scope();
+                self.declare_expr(var);
+                self.loop_body(body);
+                self.close_scope();
+            },
+            StmtKind::GenericForLoop(names, exprs, body) => {
+                self.visit_expr_mut(exprs);
+                self.open_scope();
+                self.declare_expr(names);
+                self.loop_body(body);
+                self.close_scope();
+            },
+            StmtKind::Break => {
+                if self.current().loop_depth == 0 {
+                    self.errors.push(ParseError::semantic(stmt.span, format!("break outside a loop at line {}", stmt.span.line)));
+                }
+            },
+            _ => walk_stmt_mut(self, stmt),
+        }
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        match &mut expr.kind {
+            ExprKind::Var(name, kind) => {
+                let level = self.functions.len() - 1;
+                *kind = self.find(level, name).unwrap_or(VarKind::Global);
+            },
+            ExprKind::Varargs => {
+                if !self.current().is_vararg {
+                    self.errors.push(ParseError::semantic(expr.span, "cannot use '...' outside a vararg function".into()));
+                }
+            },
+            ExprKind::Literal(Value::FunctionDef(function)) => {
+                let is_vararg = function.get_params().iter().any(|p| matches!(p.kind, ExprKind::Varargs));
+                self.functions.push(FunctionState::new(is_vararg));
+                function.get_params_mut().iter_mut().for_each(|p| self.declare_expr(p));
+                self.visit_stmt_mut(function.get_body_mut());
+                if let Some(state) = self.functions.pop() {
+                    function.set_info(state.info());
+                }
+            },
+            _ => walk_expr_mut(self, expr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser};
+    use crate::visitor::{Visitor, walk_expr};
+
+    fn parse(src: &str) -> Stmt {
+        let tokens = Lexer::new(src).tokenize().expect("source should lex");
+        Parser::new(tokens).chunk().ok().expect("source should parse")
+    }
+
+    fn resolved(src: &str) -> (Stmt, FunctionInfo) {
+        let mut chunk = parse(src);
+        let main = resolve(&mut chunk).ok().expect("source should resolve");
+        (chunk, main)
+    }
+
+    // Every variable and function of a resolved chunk, in source order
+    #[derive(Default)]
+    struct Collect {
+        vars: Vec<(usize, String, VarKind)>,
+        functions: Vec<(usize, FunctionInfo)>,
+    }
+
+    impl Visitor for Collect {
+        fn visit_expr(&mut self, expr: &Expr) {
+            match &expr.kind {
+                ExprKind::Var(name, kind) => self.vars.push((expr.span.start, name.clone(), *kind)),
+                ExprKind::Literal(Value::FunctionDef(function)) => self.functions.push((expr.span.start, function.get_info().clone())),
+                _ => {},
+            }
+            walk_expr(self, expr);
+        }
+    }
+
+    fn collect(src: &str) -> Collect {
+        let (chunk, _) = resolved(src);
+        let mut collect = Collect::default();
+        collect.visit_stmt(&chunk);
+        collect.vars.sort_by_key(|(start, _, _)| *start);
+        collect.functions.sort_by_key(|(start, _)| *start);
+        collect
+    }
+
+    fn var_kinds(src: &str) -> Vec<(String, VarKind)> {
+        collect(src).vars.into_iter().map(|(_, name, kind)| (name, kind)).collect()
+    }
+
+    // Capture lists of every function, in source order
+    fn captures(src: &str) -> Vec<Vec<(String, VarKind)>> {
+        collect(src).functions.into_iter()
+            .map(|(_, info)| info.captures.iter().map(|c| (c.name.clone(), c.source)).collect())
+            .collect()
+    }
+
+    fn errors(src: &str) -> Vec<String> {
+        match resolve(&mut parse(src)) {
+            Ok(_) => vec![],
+            Err(errors) => errors.iter().map(|e| e.message.clone()).collect(),
+        }
+    }
+
+    fn var(name: &str, kind: VarKind) -> (String, VarKind) {
+        (name.to_string(), kind)
+    }
+
+    #[test]
+    fn classifies_variables() {
+        let src = "local a = 1\nlocal a = a\nlocal function f(p) return a + p + g end\nprint(f)";
+        assert_eq!(var_kinds(src), vec![
+            var("a", VarKind::Local(0)),
+            var("a", VarKind::Local(1)),
+            var("a", VarKind::Local(0)),
+            var("f", VarKind::Local(2)),
+            var("p", VarKind::Local(0)),
+            var("a", VarKind::Upvalue(0)),
+            var("p", VarKind::Local(0)),
+            var("g", VarKind::Global),
+            var("print", VarKind::Global),
+            var("f", VarKind::Local(2)),
+        ]);
+    }
+
+    #[test]
+    fn reuses_slots_of_closed_scopes() {
+        let src = "do local a = 1 end\ndo local b, c = 2, 3 end\nfor i = 1, 2 do local d end\nlocal e = 4";
+        assert_eq!(var_kinds(src), vec![
+            var("a", VarKind::Local(0)),
+            var("b", VarKind::Local(0)),
+            var("c", VarKind::Local(1)),
+            var("i", VarKind::Local(0)),
+            var("d", VarKind::Local(1)),
+            var("e", VarKind::Local(0)),
+        ]);
+        assert_eq!(resolved(src).1.slots, 2);
+    }
+
+    #[test]
+    fn captures_through_every_enclosing_function() {
+        let src = "local x, y = 1, 2\nlocal function f()\n  return function() return y, x end, x\nend";
+        assert_eq!(captures(src), vec![
+            vec![var("y", VarKind::Local(1)), var("x", VarKind::Local(0))],
+            vec![var("y", VarKind::Upvalue(0)), var("x", VarKind::Upvalue(1))],
+        ]);
+        assert_eq!(var_kinds(src)[3..], [var("y", VarKind::Upvalue(0)), var("x", VarKind::Upvalue(1)), var("x", VarKind::Upvalue(1))]);
+    }
+
+    // The main chunk can have the same span as a function literal, each keeps its own info
+    #[test]
+    fn chunk_and_function_with_the_same_span() {
+        let (chunk, main) = resolved("function f(a) local x, y = 1, 2 return a + x + y end");
+        assert_eq!(main.slots, 0);
+        let StmtKind::Chunk(stmts) = &chunk.kind else { panic!("not a chunk") };
+        let StmtKind::Assignment(_, values) = &stmts[0].kind else { panic!("not an assignment") };
+        let ExprKind::Exprlist(values) = &values.kind else { panic!("not a list") };
+        let ExprKind::Literal(Value::FunctionDef(function)) = &values[0].kind else { panic!("not a function") };
+        assert_eq!(values[0].span, chunk.span);
+        assert_eq!(function.get_info().slots, 3);
+    }
+
+    #[test]
+    fn reports_break_outside_loops() {
+        assert!(errors("while true do break end\nfor i = 1, 2 do if i then break end end").is_empty());
+        assert_eq!(errors("break"), ["break outside a loop at line 1"]);
+        assert_eq!(errors("while true do\n  local function f() break end\nend"), ["break outside a loop at line 2"]);
+    }
+
+    #[test]
+    fn reports_varargs_outside_vararg_functions() {
+        assert!(errors("local a = ...\nlocal function f(...) return ... end").is_empty());
+        assert_eq!(errors("local function f() return ... end"), ["cannot use '...' outside a vararg function"]);
+        assert_eq!(errors("local function f(...) return function() return ... end end"), ["cannot use '...' outside a vararg function"]);
+    }
+}
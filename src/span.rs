@@ -0,0 +1,37 @@
+use std::fmt::Display;
+
+// A region of source text. Offsets are in bytes, lines and columns start at 1.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl Default for Span {
+    fn default() -> Self {
+        Self { start: 0, end: 0, line: 1, column: 1, end_line: 1, end_column: 1 }
+    }
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, column: usize, end_line: usize, end_column: usize) -> Self {
+        Self { start, end, line, column, end_line, end_column }
+    }
+
+    // Returns the smallest span covering both `self` and `other`
+    pub fn to(&self, other: Span) -> Span {
+        let (first, last) = if self.start <= other.start { (*self, other) } else { (other, *self) };
+        let end = if last.end >= first.end { last } else { first };
+        Span::new(first.start, end.end, first.line, first.column, end.end_line, end.end_column)
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
@@ -1,18 +1,48 @@
-use crate::Expr;
+use crate::{Expr, span::Span};
 #[derive(Clone)]
-pub enum Stmt {
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Clone)]
+pub enum StmtKind {
     Break,
+    Goto(String),
+    Label(String),
     Empty,
     ExprStmt(Expr),
     Assignment(Expr, Expr),
-    LocalAssignment(Expr, Expr),
+    LocalAssignment(Expr, /* names */ Expr, /* values */ Vec<Option<LocalAttrib>> /* one per name, runtime declarations leave it empty */),
+    LocalFunction(Expr, /* name, a `Var` */ Expr /* function */),
     Block(Vec<Stmt>),
     DoBlock(Vec<Stmt>),
     IfStmt(Expr, /* conditional */ Box<Stmt> /* body */, Box<Stmt> /* else stmts */),
     WhileLoop(Expr, /* conditional */ Box<Stmt> /* body */),
     RepeatUntilLoop(Box<Stmt>, Expr),
-    NumericForLoop(Expr, /* control variable */ Expr, /* control value expression */ Expr, /* limit */ Expr, /* step */ Vec<Stmt> /* body */),
+    NumericForLoop(Expr, /* control variable */ Expr, /* control value expression */ Box<Expr>, /* limit */ Box<Expr>, /* step */ Vec<Stmt> /* body */),
+    GenericForLoop(Expr, /* loop variables */ Expr, /* iterator expressions */ Vec<Stmt> /* body */),
     Return(Expr),
     // Implementation detail, not visible to users
     Chunk(Vec<Stmt>),
+}
+
+// The `<const>` and `<close>` attributes of a local declaration
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LocalAttrib {
+    Const,
+    Close,
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+// Statements created by the runtime have no place in the source
+impl From<StmtKind> for Stmt {
+    fn from(kind: StmtKind) -> Self {
+        Self { kind, span: Span::default() }
+    }
 }
\ No newline at end of file
@@ -1,4 +1,6 @@
 use crate::Value;
+use crate::span::Span;
+use std::fmt::Display;
 #[derive(PartialEq, Clone)]
 pub enum Token {
     Assign,
@@ -49,4 +51,142 @@ pub enum Token {
     Pound,
     Varargs,
     Percent,
+    Caret,
+    DoubleForwardSlash,
+    Ampersand,
+    Pipe,
+    Tilde,
+    ShiftLeft,
+    ShiftRight,
+    Colon,
+    DoubleColon,
+}
+
+// Prints the token the way it is written in Lua source, used in error messages
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Token::Literal(Value::String(s)) => return write!(f, "\"{s}\""),
+            Token::Literal(v) => return write!(f, "{v}"),
+            Token::Identifier(name) => return write!(f, "{name}"),
+            Token::Assign => "=",
+            Token::Comma => ",",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::ForwardSlash => "/",
+            Token::Equals => "==",
+            Token::NotEquals => "~=",
+            Token::LessThan => "<",
+            Token::GreaterThan => ">",
+            Token::LessThanOrEqual => "<=",
+            Token::GreaterThanOrEqual => ">=",
+            Token::LeftParens => "(",
+            Token::RightParens => ")",
+            Token::Semicolon => ";",
+            Token::Concatenation => "..",
+            Token::Do => "do",
+            Token::End => "end",
+            Token::And => "and",
+            Token::Or => "or",
+            Token::Not => "not",
+            Token::Function => "function",
+            Token::Return => "return",
+            Token::Break => "break",
+            Token::Goto => "goto",
+            Token::Nil => "nil",
+            Token::False => "false",
+            Token::True => "true",
+            Token::Local => "local",
+            Token::While => "while",
+            Token::Repeat => "repeat",
+            Token::Until => "until",
+            Token::If => "if",
+            Token::Then => "then",
+            Token::Else => "else",
+            Token::Elseif => "elseif",
+            Token::For => "for",
+            Token::In => "in",
+            Token::LeftCurlyBrace => "{",
+            Token::RightCurlyBrace => "}",
+            Token::Period => ".",
+            Token::LeftSquareBracket => "[",
+            Token::RightSquareBracket => "]",
+            Token::Pound => "#",
+            Token::Varargs => "...",
+            Token::Percent => "%",
+            Token::Caret => "^",
+            Token::DoubleForwardSlash => "//",
+            Token::Ampersand => "&",
+            Token::Pipe => "|",
+            Token::Tilde => "~",
+            Token::ShiftLeft => "<<",
+            Token::ShiftRight => ">>",
+            Token::Colon => ":",
+            Token::DoubleColon => "::",
+        };
+        write!(f, "{text}")
+    }
+}
+
+// A token together with the region of source it was lexed from
+#[derive(PartialEq, Clone)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+impl SpannedToken {
+    pub fn new(token: Token, span: Span) -> Self {
+        Self { token, span }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum TriviaKind {
+    Whitespace,
+    Newline,
+    LineComment,
+    BlockComment,
+    Shebang,
+}
+
+// Source text that carries no meaning for the parser, kept around by the lossless lexer mode
+#[derive(PartialEq, Clone, Debug)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub span: Span,
+    pub text: Vec<u8>,
+}
+
+// A token with its original text and the trivia right before it
+#[derive(PartialEq, Clone)]
+pub struct LosslessToken {
+    pub token: SpannedToken,
+    pub text: Vec<u8>,
+    pub leading_trivia: Vec<Trivia>,
+}
+
+// Output of the lossless lexer mode. Every byte of the source belongs either to a token or to a piece of trivia.
+#[derive(PartialEq, Clone)]
+pub struct LosslessTokens {
+    pub tokens: Vec<LosslessToken>,
+    pub trailing_trivia: Vec<Trivia>,
+}
+
+impl LosslessTokens {
+    // Rebuilds the source the tokens were lexed from, byte for byte
+    pub fn to_source(&self) -> Vec<u8> {
+        let mut ret = vec![];
+        for t in self.tokens.iter() {
+            for trivia in t.leading_trivia.iter() {
+                ret.extend_from_slice(&trivia.text);
+            }
+            ret.extend_from_slice(&t.text);
+        }
+        for trivia in self.trailing_trivia.iter() {
+            ret.extend_from_slice(&trivia.text);
+        }
+        ret
+    }
 }
@@ -2,23 +2,25 @@ use ordered_float::OrderedFloat;
 use crate::function::Function;
 use crate::gc::gc_key::GcKey;
 use crate::native_function::NativeFunction;
+use crate::lua_string::LuaString;
 use std::fmt::Display;
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub enum Value {
     ValList(Vec<Value>),
     Boolean(bool),
-    Number(OrderedFloat<f32>),
-    String(String),
+    Number(OrderedFloat<f64>),
+    Integer(i64),
+    String(LuaString),
     Nil,
     FunctionDef(Function),
     NativeFunctionDef(NativeFunction),
     Table(GcKey),
     // Used when interpreting break statements. Can only be created by the runtime, not the user
     Interrupt,
+    // Used when interpreting goto statements, carries the target label. Can only be created by the runtime
+    Goto(String),
     // Used for storing metatables in tables
     MetaKey,
-    Varargs(Vec<Value>),
-    VarargsIdentifier,
 
 }
 
@@ -29,7 +31,8 @@ impl Display for Value {
             Value::FunctionDef(_) => write!(f, "<function definition>"),
             Value::NativeFunctionDef(_) => write!(f, "<native function definition>"),
             Value::Nil => write!(f, "nil"),
-            Value::Number(n) => write!(f, "{n}"),
+            Value::Number(n) => write!(f, "{}", fmt_float(n.0)),
+            Value::Integer(i) => write!(f, "{i}"),
             Value::String(s) => write!(f, "{s}"),
             Value::Table(_) => write!(f, "<table>"),
             Value::ValList(vl) => {
@@ -41,16 +44,36 @@ impl Display for Value {
                 std::fmt::Result::Ok(())
             },
             Value::Interrupt => panic!("Unprintable value"),
+            Value::Goto(_) => panic!("Unprintable value"),
             Value::MetaKey => panic!("Unprintable value"),
-            Value::Varargs(va) => {
-                for v in va.iter() {
-                    if let std::fmt::Result::Err(e) = write!(f, "{v}\t") {
-                        return std::fmt::Result::Err(e);
-                    }
-                }
-                std::fmt::Result::Ok(())
-            },
-            Value::VarargsIdentifier => write!(f, "<varargs>"),
         }
     }
-}
\ No newline at end of file
+}
+
+// Formats a float like Lua's "%.14g", keeping a ".0" suffix on integral values so they can be told apart from integers
+pub fn fmt_float(n: f64) -> String {
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan".into() } else { "nan".into() };
+    } else if n.is_infinite() {
+        return if n < 0.0 { "-inf".into() } else { "inf".into() };
+    } else if n == 0.0 {
+        return if n.is_sign_negative() { "-0.0".into() } else { "0.0".into() };
+    }
+    // Round to 14 significant digits first, the exponent can change while rounding (e.g. 9.99..e9 -> 1e10)
+    let sci = format!("{:.13e}", n);
+    let (mantissa, exponent) = sci.split_once('e').unwrap_or((&sci, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    if !(-4..14).contains(&exponent) {
+        let mantissa = mantissa.trim_end_matches('0').trim_end_matches('.');
+        let sign = if exponent < 0 { '-' } else { '+' };
+        return format!("{mantissa}e{sign}{:02}", exponent.abs());
+    }
+    let decimals = (13 - exponent).max(0) as usize;
+    let fixed = format!("{:.*}", decimals, n);
+    let fixed = if fixed.contains('.') { fixed.trim_end_matches('0').trim_end_matches('.').to_string() } else { fixed };
+    if fixed.contains('.') {
+        fixed
+    } else {
+        fixed + ".0"
+    }
+}
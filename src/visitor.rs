@@ -0,0 +1,453 @@
+use crate::{Expr, Stmt, Value, expr::{ExprKind, Field}, stmt::StmtKind, function::Function};
+
+// Traversal of the syntax tree for analysis passes. Every method walks into the children of its node by default,
+// so a pass only overrides the nodes it cares about and calls the matching `walk_*` function to keep descending.
+// Function bodies are reached through `visit_function` from the literal that defines them.
+pub trait Visitor {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        walk_stmt(self, stmt)
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr)
+    }
+
+    fn visit_function(&mut self, function: &Function) {
+        walk_function(self, function)
+    }
+}
+
+pub fn walk_stmt<V: Visitor + ?Sized>(visitor: &mut V, stmt: &Stmt) {
+    match &stmt.kind {
+        StmtKind::Break | StmtKind::Goto(_) | StmtKind::Label(_) | StmtKind::Empty => {},
+        StmtKind::ExprStmt(expr) | StmtKind::Return(expr) => visitor.visit_expr(expr),
+        StmtKind::Assignment(targets, values) | StmtKind::LocalAssignment(targets, values, _) | StmtKind::LocalFunction(targets, values) => {
+            visitor.visit_expr(targets);
+            visitor.visit_expr(values);
+        },
+        StmtKind::Block(stmts) | StmtKind::DoBlock(stmts) | StmtKind::Chunk(stmts) => {
+            stmts.iter().for_each(|s| visitor.visit_stmt(s));
+        },
+        StmtKind::IfStmt(cond, body, else_body) => {
+            visitor.visit_expr(cond);
+            visitor.visit_stmt(body);
+            visitor.visit_stmt(else_body);
+        },
+        StmtKind::WhileLoop(cond, body) => {
+            visitor.visit_expr(cond);
+            visitor.visit_stmt(body);
+        },
+        StmtKind::RepeatUntilLoop(body, cond) => {
+            visitor.visit_stmt(body);
+            visitor.visit_expr(cond);
+        },
+        StmtKind::NumericForLoop(var, start, limit, step, body) => {
+            visitor.visit_expr(var);
+            visitor.visit_expr(start);
+            visitor.visit_expr(limit);
+            visitor.visit_expr(step);
+            body.iter().for_each(|s| visitor.visit_stmt(s));
+        },
+        StmtKind::GenericForLoop(names, exprs, body) => {
+            visitor.visit_expr(names);
+            visitor.visit_expr(exprs);
+            body.iter().for_each(|s| visitor.visit_stmt(s));
+        },
+    }
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match &expr.kind {
+        ExprKind::Binary(left, _, right) | ExprKind::Accessor(left, right) => {
+            visitor.visit_expr(left);
+            visitor.visit_expr(right);
+        },
+        ExprKind::Unary(operand, _) | ExprKind::Grouping(operand) => visitor.visit_expr(operand),
+        ExprKind::Literal(Value::FunctionDef(function)) => visitor.visit_function(function),
+        ExprKind::Literal(_) | ExprKind::Var(..) | ExprKind::Varargs => {},
+        ExprKind::Exprlist(exprs) => exprs.iter().for_each(|e| visitor.visit_expr(e)),
+        ExprKind::FunctionCall(callee, args) | ExprKind::MethodCall(callee, _, args) => {
+            visitor.visit_expr(callee);
+            args.iter().for_each(|e| visitor.visit_expr(e));
+        },
+        ExprKind::FieldList(fields) => {
+            for field in fields.iter() {
+                match field {
+                    Field::Positional(value) => visitor.visit_expr(value),
+                    Field::Keyed(key, value) => {
+                        visitor.visit_expr(key);
+                        visitor.visit_expr(value);
+                    },
+                }
+            }
+        },
+    }
+}
+
+pub fn walk_function<V: Visitor + ?Sized>(visitor: &mut V, function: &Function) {
+    function.get_params().iter().for_each(|p| visitor.visit_expr(p));
+    visitor.visit_stmt(function.get_body());
+}
+
+// Like `Visitor`, but with mutable access so a pass can rewrite nodes in place
+pub trait MutVisitor {
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        walk_stmt_mut(self, stmt)
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr)
+    }
+
+    fn visit_function_mut(&mut self, function: &mut Function) {
+        walk_function_mut(self, function)
+    }
+}
+
+pub fn walk_stmt_mut<V: MutVisitor + ?Sized>(visitor: &mut V, stmt: &mut Stmt) {
+    match &mut stmt.kind {
+        StmtKind::Break | StmtKind::Goto(_) | StmtKind::Label(_) | StmtKind::Empty => {},
+        StmtKind::ExprStmt(expr) | StmtKind::Return(expr) => visitor.visit_expr_mut(expr),
+        StmtKind::Assignment(targets, values) | StmtKind::LocalAssignment(targets, values, _) | StmtKind::LocalFunction(targets, values) => {
+            visitor.visit_expr_mut(targets);
+            visitor.visit_expr_mut(values);
+        },
+        StmtKind::Block(stmts) | StmtKind::DoBlock(stmts) | StmtKind::Chunk(stmts) => {
+            stmts.iter_mut().for_each(|s| visitor.visit_stmt_mut(s));
+        },
+        StmtKind::IfStmt(cond, body, else_body) => {
+            visitor.visit_expr_mut(cond);
+            visitor.visit_stmt_mut(body);
+            visitor.visit_stmt_mut(else_body);
+        },
+        StmtKind::WhileLoop(cond, body) => {
+            visitor.visit_expr_mut(cond);
+            visitor.visit_stmt_mut(body);
+        },
+        StmtKind::RepeatUntilLoop(body, cond) => {
+            visitor.visit_stmt_mut(body);
+            visitor.visit_expr_mut(cond);
+        },
+        StmtKind::NumericForLoop(var, start, limit, step, body) => {
+            visitor.visit_expr_mut(var);
+            visitor.visit_expr_mut(start);
+            visitor.visit_expr_mut(limit);
+            visitor.visit_expr_mut(step);
+            body.iter_mut().for_each(|s| visitor.visit_stmt_mut(s));
+        },
+        StmtKind::GenericForLoop(names, exprs, body) => {
+            visitor.visit_expr_mut(names);
+            visitor.visit_expr_mut(exprs);
+            body.iter_mut().for_each(|s| visitor.visit_stmt_mut(s));
+        },
+    }
+}
+
+pub fn walk_expr_mut<V: MutVisitor + ?Sized>(visitor: &mut V, expr: &mut Expr) {
+    match &mut expr.kind {
+        ExprKind::Binary(left, _, right) | ExprKind::Accessor(left, right) => {
+            visitor.visit_expr_mut(left);
+            visitor.visit_expr_mut(right);
+        },
+        ExprKind::Unary(operand, _) | ExprKind::Grouping(operand) => visitor.visit_expr_mut(operand),
+        ExprKind::Literal(Value::FunctionDef(function)) => visitor.visit_function_mut(function),
+        ExprKind::Literal(_) | ExprKind::Var(..) | ExprKind::Varargs => {},
+        ExprKind::Exprlist(exprs) => exprs.iter_mut().for_each(|e| visitor.visit_expr_mut(e)),
+        ExprKind::FunctionCall(callee, args) | ExprKind::MethodCall(callee, _, args) => {
+            visitor.visit_expr_mut(callee);
+            args.iter_mut().for_each(|e| visitor.visit_expr_mut(e));
+        },
+        ExprKind::FieldList(fields) => {
+            for field in fields.iter_mut() {
+                match field {
+                    Field::Positional(value) => visitor.visit_expr_mut(value),
+                    Field::Keyed(key, value) => {
+                        visitor.visit_expr_mut(key);
+                        visitor.visit_expr_mut(value);
+                    },
+                }
+            }
+        },
+    }
+}
+
+pub fn walk_function_mut<V: MutVisitor + ?Sized>(visitor: &mut V, function: &mut Function) {
+    function.get_params_mut().iter_mut().for_each(|p| visitor.visit_expr_mut(p));
+    visitor.visit_stmt_mut(function.get_body_mut());
+}
+
+// Rebuilds the tree bottom-up, taking each node by value and returning its replacement.
+// Useful for passes that turn a node into one of a different kind, e.g. a binary expression into a literal.
+pub trait Folder {
+    fn fold_stmt(&mut self, stmt: Stmt) -> Stmt {
+        walk_fold_stmt(self, stmt)
+    }
+
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        walk_fold_expr(self, expr)
+    }
+
+    fn fold_function(&mut self, function: Function) -> Function {
+        walk_fold_function(self, function)
+    }
+}
+
+fn fold_stmts<F: Folder + ?Sized>(folder: &mut F, stmts: Vec<Stmt>) -> Vec<Stmt> {
+    stmts.into_iter().map(|s| folder.fold_stmt(s)).collect()
+}
+
+// Folds the boxed expression in place, reusing its allocation
+fn fold_box<F: Folder + ?Sized>(folder: &mut F, mut expr: Box<Expr>) -> Box<Expr> {
+    *expr = folder.fold_expr(*expr);
+    expr
+}
+
+pub fn walk_fold_stmt<F: Folder + ?Sized>(folder: &mut F, stmt: Stmt) -> Stmt {
+    let kind = match stmt.kind {
+        kind @ (StmtKind::Break | StmtKind::Goto(_) | StmtKind::Label(_) | StmtKind::Empty) => kind,
+        StmtKind::ExprStmt(expr) => StmtKind::ExprStmt(folder.fold_expr(expr)),
+        StmtKind::Assignment(targets, values) => StmtKind::Assignment(folder.fold_expr(targets), folder.fold_expr(values)),
+        StmtKind::LocalAssignment(names, values, attribs) => {
+            StmtKind::LocalAssignment(folder.fold_expr(names), folder.fold_expr(values), attribs)
+        },
+        StmtKind::LocalFunction(name, expr) => StmtKind::LocalFunction(folder.fold_expr(name), folder.fold_expr(expr)),
+        StmtKind::Block(stmts) => StmtKind::Block(fold_stmts(folder, stmts)),
+        StmtKind::DoBlock(stmts) => StmtKind::DoBlock(fold_stmts(folder, stmts)),
+        StmtKind::Chunk(stmts) => StmtKind::Chunk(fold_stmts(folder, stmts)),
+        StmtKind::IfStmt(cond, body, else_body) => {
+            let cond = folder.fold_expr(cond);
+            StmtKind::IfStmt(cond, Box::new(folder.fold_stmt(*body)), Box::new(folder.fold_stmt(*else_body)))
+        },
+        StmtKind::WhileLoop(cond, body) => {
+            let cond = folder.fold_expr(cond);
+            StmtKind::WhileLoop(cond, Box::new(folder.fold_stmt(*body)))
+        },
+        StmtKind::RepeatUntilLoop(body, cond) => {
+            let body = Box::new(folder.fold_stmt(*body));
+            StmtKind::RepeatUntilLoop(body, folder.fold_expr(cond))
+        },
+        StmtKind::NumericForLoop(var, start, limit, step, body) => StmtKind::NumericForLoop(
+            folder.fold_expr(var),
+            folder.fold_expr(start),
+            fold_box(folder, limit),
+            fold_box(folder, step),
+            fold_stmts(folder, body),
+        ),
+        StmtKind::GenericForLoop(names, exprs, body) => {
+            StmtKind::GenericForLoop(folder.fold_expr(names), folder.fold_expr(exprs), fold_stmts(folder, body))
+        },
+        StmtKind::Return(expr) => StmtKind::Return(folder.fold_expr(expr)),
+    };
+    Stmt::new(kind, stmt.span)
+}
+
+pub fn walk_fold_expr<F: Folder + ?Sized>(folder: &mut F, expr: Expr) -> Expr {
+    let kind = match expr.kind {
+        ExprKind::Binary(left, op, right) => {
+            let left = fold_box(folder, left);
+            ExprKind::Binary(left, op, fold_box(folder, right))
+        },
+        ExprKind::Unary(operand, op) => ExprKind::Unary(fold_box(folder, operand), op),
+        ExprKind::Literal(Value::FunctionDef(function)) => ExprKind::Literal(Value::FunctionDef(folder.fold_function(function))),
+        kind @ (ExprKind::Literal(_) | ExprKind::Var(..) | ExprKind::Varargs) => kind,
+        ExprKind::Grouping(inner) => ExprKind::Grouping(fold_box(folder, inner)),
+        ExprKind::Exprlist(exprs) => ExprKind::Exprlist(exprs.into_iter().map(|e| folder.fold_expr(e)).collect()),
+        ExprKind::FunctionCall(callee, args) => {
+            let callee = fold_box(folder, callee);
+            ExprKind::FunctionCall(callee, args.into_iter().map(|e| folder.fold_expr(e)).collect())
+        },
+        ExprKind::MethodCall(receiver, name, args) => {
+            let receiver = fold_box(folder, receiver);
+            ExprKind::MethodCall(receiver, name, args.into_iter().map(|e| folder.fold_expr(e)).collect())
+        },
+        ExprKind::Accessor(table, key) => {
+            let table = fold_box(folder, table);
+            ExprKind::Accessor(table, fold_box(folder, key))
+        },
+        ExprKind::FieldList(fields) => ExprKind::FieldList(
+            fields.into_iter().map(|field| match field {
+                Field::Positional(value) => Field::Positional(fold_box(folder, value)),
+                Field::Keyed(key, value) => {
+                    let key = fold_box(folder, key);
+                    Field::Keyed(key, fold_box(folder, value))
+                },
+            }).collect()
+        ),
+    };
+    Expr::new(kind, expr.span)
+}
+
+// Function definitions are folded in place, a `Function` keeps its identity and closure
+pub fn walk_fold_function<F: Folder + ?Sized>(folder: &mut F, mut function: Function) -> Function {
+    let params = std::mem::take(function.get_params_mut());
+    *function.get_params_mut() = params.into_iter().map(|p| folder.fold_expr(p)).collect();
+    let body = std::mem::replace(function.get_body_mut(), Stmt::from(StmtKind::Empty));
+    *function.get_body_mut() = folder.fold_stmt(body);
+    function
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::{lexer::Lexer, parser::Parser};
+
+    // Uses every statement and expression kind the parser produces, some of them only inside a function body
+    const SOURCE: &str = "
+        local a <const>, b = 1, -2
+        local function f(x, ...)
+            local t = {x, k = (...), [1 + 2] = #x}
+            t.k, t[1] = t:m(\"s\"), nil
+            return t
+        end
+        ;
+        do g(a) end
+        if a then b = 1 else b = 2 end
+        while true do break end
+        repeat ::top:: goto top until a
+        for i = 1, 10, 2 do end
+        for k, v in pairs(f) do end
+        return a
+    ";
+
+    fn stmt_name(stmt: &Stmt) -> &'static str {
+        match stmt.kind {
+            StmtKind::Break => "Break",
+            StmtKind::Goto(_) => "Goto",
+            StmtKind::Label(_) => "Label",
+            StmtKind::Empty => "Empty",
+            StmtKind::ExprStmt(_) => "ExprStmt",
+            StmtKind::Assignment(..) => "Assignment",
+            StmtKind::LocalAssignment(..) => "LocalAssignment",
+            StmtKind::LocalFunction(..) => "LocalFunction",
+            StmtKind::Block(_) => "Block",
+            StmtKind::DoBlock(_) => "DoBlock",
+            StmtKind::IfStmt(..) => "IfStmt",
+            StmtKind::WhileLoop(..) => "WhileLoop",
+            StmtKind::RepeatUntilLoop(..) => "RepeatUntilLoop",
+            StmtKind::NumericForLoop(..) => "NumericForLoop",
+            StmtKind::GenericForLoop(..) => "GenericForLoop",
+            StmtKind::Return(_) => "Return",
+            StmtKind::Chunk(_) => "Chunk",
+        }
+    }
+
+    fn expr_name(expr: &Expr) -> &'static str {
+        match expr.kind {
+            ExprKind::Binary(..) => "Binary",
+            ExprKind::Unary(..) => "Unary",
+            ExprKind::Literal(_) => "Literal",
+            ExprKind::Grouping(_) => "Grouping",
+            ExprKind::Var(..) => "Var",
+            ExprKind::Exprlist(_) => "Exprlist",
+            ExprKind::FunctionCall(..) => "FunctionCall",
+            ExprKind::MethodCall(..) => "MethodCall",
+            ExprKind::Accessor(..) => "Accessor",
+            ExprKind::FieldList(_) => "FieldList",
+            ExprKind::Varargs => "Varargs",
+        }
+    }
+
+    #[derive(Default)]
+    struct Counter {
+        counts: BTreeMap<&'static str, usize>,
+    }
+
+    impl Counter {
+        fn count(&mut self, name: &'static str) {
+            *self.counts.entry(name).or_default() += 1;
+        }
+    }
+
+    impl Visitor for Counter {
+        fn visit_stmt(&mut self, stmt: &Stmt) {
+            self.count(stmt_name(stmt));
+            walk_stmt(self, stmt);
+        }
+
+        fn visit_expr(&mut self, expr: &Expr) {
+            self.count(expr_name(expr));
+            walk_expr(self, expr);
+        }
+
+        fn visit_function(&mut self, function: &Function) {
+            self.count("Function");
+            walk_function(self, function);
+        }
+    }
+
+    impl MutVisitor for Counter {
+        fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+            self.count(stmt_name(stmt));
+            walk_stmt_mut(self, stmt);
+        }
+
+        fn visit_expr_mut(&mut self, expr: &mut Expr) {
+            self.count(expr_name(expr));
+            walk_expr_mut(self, expr);
+        }
+
+        fn visit_function_mut(&mut self, function: &mut Function) {
+            self.count("Function");
+            walk_function_mut(self, function);
+        }
+    }
+
+    impl Folder for Counter {
+        fn fold_stmt(&mut self, stmt: Stmt) -> Stmt {
+            self.count(stmt_name(&stmt));
+            walk_fold_stmt(self, stmt)
+        }
+
+        fn fold_expr(&mut self, expr: Expr) -> Expr {
+            self.count(expr_name(&expr));
+            walk_fold_expr(self, expr)
+        }
+
+        fn fold_function(&mut self, function: Function) -> Function {
+            self.count("Function");
+            walk_fold_function(self, function)
+        }
+    }
+
+    fn parse() -> Stmt {
+        let tokens = Lexer::new(SOURCE).tokenize().expect("source should lex");
+        Parser::new(tokens).chunk().ok().expect("source should parse")
+    }
+
+    fn expected_counts() -> BTreeMap<&'static str, usize> {
+        BTreeMap::from([
+            ("Chunk", 1), ("LocalAssignment", 2), ("LocalFunction", 1), ("Assignment", 3), ("Return", 2),
+            ("Empty", 1), ("DoBlock", 1), ("ExprStmt", 1), ("IfStmt", 1), ("Block", 5), ("WhileLoop", 1),
+            ("Break", 1), ("RepeatUntilLoop", 1), ("Label", 1), ("Goto", 1), ("NumericForLoop", 1),
+            ("GenericForLoop", 1), ("Function", 1),
+            ("Binary", 1), ("Unary", 2), ("Literal", 16), ("Grouping", 1), ("Var", 23), ("Exprlist", 16),
+            ("FunctionCall", 2), ("MethodCall", 1), ("Accessor", 2), ("FieldList", 1), ("Varargs", 2),
+        ])
+    }
+
+    #[test]
+    fn visitor_reaches_every_node() {
+        let mut counter = Counter::default();
+        counter.visit_stmt(&parse());
+        assert_eq!(counter.counts, expected_counts());
+    }
+
+    #[test]
+    fn mut_visitor_reaches_every_node() {
+        let mut counter = Counter::default();
+        counter.visit_stmt_mut(&mut parse());
+        assert_eq!(counter.counts, expected_counts());
+    }
+
+    #[test]
+    fn folder_reaches_every_node() {
+        let mut counter = Counter::default();
+        let folded = counter.fold_stmt(parse());
+        assert_eq!(counter.counts, expected_counts());
+        // Folding without changes gives back the same tree
+        let mut again = Counter::default();
+        again.visit_stmt(&folded);
+        assert_eq!(again.counts, expected_counts());
+    }
+}
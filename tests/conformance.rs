@@ -0,0 +1,26 @@
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn redmoon(args: &[&str], file: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_redmoon")).args(args).arg(file).output().expect("could not run redmoon")
+}
+
+// Every script prints what its `.out` file holds, with and without the optimizer
+#[test]
+fn scripts_print_expected_output() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/conformance");
+    let mut scripts: Vec<_> = std::fs::read_dir(&dir).expect("could not list conformance scripts")
+        .map(|entry| entry.expect("could not read conformance directory").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lua"))
+        .collect();
+    scripts.sort();
+    assert!(!scripts.is_empty());
+    for script in scripts.iter() {
+        let expected = std::fs::read_to_string(script.with_extension("out")).expect("every script should have an .out file");
+        for args in [&[][..], &["--no-optimize"][..]] {
+            let output = redmoon(args, script);
+            assert!(output.status.success(), "{} {:?}", script.display(), args);
+            assert_eq!(String::from_utf8_lossy(&output.stdout), expected, "{} {:?}", script.display(), args);
+        }
+    }
+}
@@ -0,0 +1,12 @@
+local t = {}
+local i = 1
+i, t[i] = i + 1, 20
+print(t[1])   --> 20
+print(t[2])   --> nil
+local a = {b = {}}
+a.b["c"], a.x = 5
+print(a.b.c)  --> 5
+print(a.x)    --> nil
+local function f() return t end
+f().y = 3
+print(t.y)    --> 3
@@ -0,0 +1,34 @@
+local log = ""
+local function closable(name)
+  return setmetatable({}, {__close = function() log = log .. name .. " " end})
+end
+
+do
+  local a <close> = closable("a")
+  local b <close> = closable("b")
+  local c <const> = 5
+  log = log .. c .. " "
+end
+print(log)              --> 5 b a
+
+log = ""
+for i = 1, 3 do
+  local x <close> = closable("x" .. i)
+  if i == 2 then break end
+end
+print(log)              --> x1 x2
+
+log = ""
+local function f()
+  local y <close> = closable("y")
+  return "ret"
+end
+print(f() .. " " .. log)  --> ret y
+
+local n <const> = 42
+do
+  local n = 1
+  n = n + 1
+  print(n)              --> 2
+end
+print(n)                --> 42
@@ -0,0 +1,9 @@
+#!/usr/bin/env redmoon
+-- line comment
+--[[ a block
+comment ]] print("code after a block comment runs")
+--[==[ a level 2 comment
+]] is still inside the comment
+]==] print("code after a level 2 comment runs")
+x = 10 --[[ inline ]] - 3
+print(x)  --> 7
@@ -0,0 +1,27 @@
+-- Every line prints the same whether or not the optimizer folded it
+if "" then print("empty strings are true") end  --> empty strings are true
+if 0 then print("zero is true") end             --> zero is true
+print(1 > 0)                                    --> true
+print(1 >= 2)                                   --> false
+print(2 >= 1.5)                                 --> true
+print("b" > "a")                                --> true
+print("a" <= "a")                               --> true
+print("10" < "9")                               --> true
+print("a" < "ab")                               --> true
+print(9007199254740993 < 9007199254740992.0)    --> false
+print(9007199254740993 == 9007199254740992.0)   --> false
+print(1 == 1.0)                                 --> true
+print("1" == 1)                                 --> false
+print(0/0 == 0/0)                               --> false
+print(0/0 >= 0/0)                               --> false
+print(1 < 0/0)                                  --> false
+print(not "")                                   --> false
+print(("" or "z") .. "!")                       --> !
+print(0 and "y")                                --> y
+print(-7 // 2)                                  --> -4
+print(-7.5 % 2)                                 --> 0.5
+print(2 ^ 10)                                   --> 1024.0
+print("a" .. 1)                                 --> a1
+print(1 << 64)                                  --> 0
+print(-1 >> 1)                                  --> 9223372036854775807
+print(9223372036854775807 + 1)                  --> -9223372036854775808
@@ -0,0 +1,22 @@
+print(2 * 60 * 60)      --> 7200
+print("a" .. "b" .. 1)  --> ab1
+print(7 % -3)           --> -2
+print(-7 // 2.0)        --> -4.0
+print(1 << 63)          --> -9223372036854775808
+print(nil and x)        --> nil
+print(false or "y")     --> y
+if false then
+  print("dead")
+else
+  print("live")         --> live
+end
+if nil then
+  print("dead")
+elseif true then
+  local z = 5
+  print(z)              --> 5
+end
+print(z)                --> nil
+while false do print("dead") end
+local ok = 1 < 2 and "yes" or "no"
+print(ok)               --> yes
@@ -0,0 +1,29 @@
+local t = {10, 20, 30, x = 1}
+local sum = 0
+for i, v in ipairs(t) do sum = sum + i * v end
+print(sum)              --> 140
+
+local n = 0
+for k, v in pairs(t) do n = n + 1 end
+print(n)                --> 4
+
+local fns = {}
+for i, v in ipairs(t) do fns[i] = function() return v end end
+print(fns[1]() + fns[3]())  --> 40
+
+local function range(max)
+  return function(_, i) if i < max then return i + 1 end end, nil, 0
+end
+for i in range(3) do
+  print(i)              --> 1
+  if i == 1 then break end
+end
+
+local closed = 0
+local tbc = setmetatable({}, {__close = function() closed = closed + 1 end})
+local function closing_range(max)
+  return function(_, i) if i < max then return i + 1 end end, nil, 0, tbc
+end
+for i in closing_range(2) do end
+for i in closing_range(5) do break end
+print(closed)           --> 2
@@ -0,0 +1,30 @@
+for i = 1, 5 do
+  if i % 2 == 0 then goto continue end
+  local sq = i * i
+  print(sq)             --> 1, 9, 25
+  ::continue::
+end
+local state = "a"
+local out = ""
+::dispatch::
+if state == "a" then out = out .. "a" state = "b" goto dispatch
+elseif state == "b" then out = out .. "b" state = "done" goto dispatch
+end
+print(out)              --> ab
+do
+  local n = 0
+  ::top::
+  n = n + 1
+  if n < 3 then goto top end
+  print(n)
+end
+local function f()
+  for i = 1, 3 do
+    for j = 1, 3 do
+      if j == 2 then goto next_i end
+      print(i * 10 + j)
+    end
+    ::next_i::
+  end
+end
+f()
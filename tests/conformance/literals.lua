@@ -0,0 +1,14 @@
+print(0xFF)           --> 255
+print(1e2)            --> 100.0
+print(0x1p4)          --> 16.0
+print(.5e3)           --> 500.0
+
+print("tab\there")
+print("\"quoted\"")
+print("\72\101\108\108\111")  --> Hello
+print("\x52\x75\x73\x74")     --> Rust
+print("\u{2603}")             --> snowman
+print("one \z
+       line")                 --> one line
+print([[
+first line]])
@@ -0,0 +1,24 @@
+local function fib(n)
+  if n < 2 then return n end
+  return fib(n - 1) + fib(n - 2)
+end
+print(fib(10))          --> 55
+
+do
+  local function countdown(n)
+    if n == 0 then return "done" end
+    return countdown(n - 1)
+  end
+  print(countdown(3))   --> done
+end
+print(countdown == nil) --> true
+
+local function counter()
+  local c = 0
+  return function() c = c + 1 return c end
+end
+local c1 = counter()
+c1()
+local c2 = counter()
+print(c1())             --> 2
+print(c2())             --> 1
@@ -0,0 +1,21 @@
+local Account = {balance = 0}
+function Account:deposit(v)
+  self.balance = self.balance + v
+  return self.balance
+end
+local a = {balance = 10, deposit = Account.deposit}
+print(a:deposit(5))     --> 15
+print(a.balance)        --> 15
+
+local calls = 0
+local function get() calls = calls + 1 return a end
+get():deposit(1)
+print(calls)            --> 1
+
+local ns = {inner = {}}
+function ns.inner.greet(name) return "hi " .. name end
+print(ns.inner.greet "bob")  --> hi bob
+
+local s = {name = "x"}
+function s:show(t) return self.name .. t[1] end
+print(s:show{"!"})      --> x!
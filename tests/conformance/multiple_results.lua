@@ -0,0 +1,39 @@
+local function three() return 1, 2, 3 end
+local function none() end
+local function count(...) return #{...} end
+local function pass(...) return ... end
+
+print(count(three()))       --> 3
+print(count(three(), 10))   --> 2
+print(count((three())))     --> 1
+print(count(none()))        --> 0
+print(count(pass(4, 5)))    --> 2
+
+local t = {three(), three()}
+print(t[4])                 --> 3
+print(t[5])                 --> nil
+t = {(three())}
+print(t[2])                 --> nil
+
+local a, b, c, d = three()
+print(c)                    --> 3
+print(d)                    --> nil
+a, b, c = three(), 10
+print(b)                    --> 10
+print(c)                    --> nil
+
+local function tail() return three() end
+local function first() return (three()) end
+local x, y, z = tail()
+print(z)                    --> 3
+x, y = first()
+print(y)                    --> nil
+print(three() + 10)         --> 11
+
+local function nested(...)
+    if true then
+        local m, n = ...
+        return n
+    end
+end
+print(nested(7, 8))         --> 8
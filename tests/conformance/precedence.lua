@@ -0,0 +1,14 @@
+print(2^3^2)            --> 512.0
+print(-2^2)             --> -4.0
+print(2^-1)             --> 0.5
+print("a".."b".."c")    --> abc
+print(1+2*3-4/2)        --> 5.0
+print(1 .. 2 == "12")   --> true
+print(not nil == true)  --> true
+print(nil or false and 1)  --> false
+print(false and 1 or 2) --> 2
+print(1 < 2 == true)    --> true
+print(5 // 2 * 2 + 5 % 2)  --> 5
+print(1 | 2 ~ 3 & 4 << 1)  --> 3
+print(#"abc" + 1)       --> 4
+print(- - 2)            --> 2
@@ -0,0 +1,54 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// A file in the temporary directory holding `contents`, unique to this test run
+fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("redmoon-fmt-{}-{name}.lua", std::process::id()));
+    std::fs::write(&path, contents).expect("could not write temporary file");
+    path
+}
+
+fn redmoon(args: &[&str], file: &PathBuf) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_redmoon")).args(args).arg(file).output().expect("could not run redmoon")
+}
+
+#[test]
+fn check_accepts_formatted_file() {
+    let file = temp_file("formatted", b"local x = 1\n");
+    let out = redmoon(&["fmt", "--check"], &file);
+    assert_eq!(out.status.code(), Some(0));
+    assert!(out.stdout.is_empty());
+    std::fs::remove_file(&file).ok();
+}
+
+#[test]
+fn check_rejects_unformatted_file_without_writing() {
+    let src = b"local  x=1\n";
+    let file = temp_file("unformatted", src);
+    let out = redmoon(&["fmt", "--check"], &file);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stdout).ends_with("is not formatted\n"));
+    assert_eq!(std::fs::read(&file).unwrap(), src);
+    std::fs::remove_file(&file).ok();
+}
+
+#[test]
+fn rewrites_file_byte_for_byte() {
+    let file = temp_file("latin1", b"-- caf\xe9\nlocal  s='\xff'\n");
+    let out = redmoon(&["fmt"], &file);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(std::fs::read(&file).unwrap(), b"-- caf\xe9\nlocal s = \"\xff\"\n");
+    // A second run has nothing left to change
+    assert_eq!(redmoon(&["fmt", "--check"], &file).status.code(), Some(0));
+    std::fs::remove_file(&file).ok();
+}
+
+#[test]
+fn fails_on_syntax_errors() {
+    let src = b"x = = 1\n";
+    let file = temp_file("invalid", src);
+    let out = redmoon(&["fmt"], &file);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(std::fs::read(&file).unwrap(), src);
+    std::fs::remove_file(&file).ok();
+}